@@ -0,0 +1,12 @@
+"""A python worker that acks after a second and then sleeps for a
+while before exiting."""
+
+import time
+
+import kleinhirn
+
+
+def main():
+    time.sleep(1)
+    kleinhirn.done()
+    time.sleep(6)
@@ -7,4 +7,10 @@ require 'sorbet-runtime'
 # it running, and knows how to fork new worker processes.
 module KleinhirnLoader
   DEFAULT_STATUS_FD = 3
+
+  # The version of the supervisor protocol that this loader speaks.
+  PROTOCOL_VERSION = 1
+
+  # Optional protocol features that this loader supports.
+  CAPABILITIES = T.let(%w[spawn_expression ack_token requests].freeze, T::Array[String])
 end
@@ -66,6 +66,7 @@ module KleinhirnLoader
       status_fd = T.let(IO.new(options.fetch(:status_fd)), IO)
       kh = KleinhirnLoader::Loader.new(options[:name], options[:version],
                                        entrypoint, status_fd)
+      kh.hello
       load_files.each { |f| kh.load_entrypoint(f) }
       kh.repl
     end
@@ -25,7 +25,13 @@ module KleinhirnLoader
             return Error.new(line, RuntimeError.new('must include an "id" field'))
           end
 
-          Spawn.new(obj['id'])
+          Spawn.new(obj['id'], obj['index'], obj['expression'], obj['token'])
+        when Kind::Forward
+          unless obj.include?('id') && obj['request'].is_a?(Hash)
+            return Error.new(line, RuntimeError.new('must include an "id" and a "request" object'))
+          end
+
+          Forward.new(obj['id'], obj['request'])
         else
           T.absurd(kind)
         end
@@ -40,15 +46,54 @@ module KleinhirnLoader
       extend T::Helpers
 
       sig do
-        params(id: String)
+        params(id: String, index: T.nilable(Integer), expression: T.nilable(String), token: T.nilable(String))
           .void
       end
-      def initialize(id)
+      def initialize(id, index, expression = nil, token = nil)
         @id = id
+        @index = index
+        @expression = expression
+        @token = token
       end
 
       sig { returns(String) }
       attr_reader :id
+
+      # The worker's slot index, a number in 0...count (or past it, for
+      # a replacement that starts before the old worker is gone).
+      sig { returns(T.nilable(Integer)) }
+      attr_reader :index
+
+      # The expression that starts the worker, if it is not the one
+      # given on the command line.
+      sig { returns(T.nilable(String)) }
+      attr_reader :expression
+
+      # The token that the worker has to present when it acks.
+      sig { returns(T.nilable(String)) }
+      attr_reader :token
+    end
+
+    # The 'forward' command: a request for the worker `id`.
+    class Forward < Command
+      extend T::Sig
+      extend T::Helpers
+
+      sig do
+        params(id: String, request: T::Hash[String, T.untyped])
+          .void
+      end
+      def initialize(id, request)
+        @id = id
+        @request = request
+      end
+
+      sig { returns(String) }
+      attr_reader :id
+
+      # The request to pass on to the worker, as the supervisor sent it.
+      sig { returns(T::Hash[String, T.untyped]) }
+      attr_reader :request
     end
 
     # An error reading a command. Not an actual command.
@@ -73,6 +118,7 @@ module KleinhirnLoader
     class Kind < T::Enum
       enums do
         Spawn = new('spawn')
+        Forward = new('forward')
       end
     end
   end
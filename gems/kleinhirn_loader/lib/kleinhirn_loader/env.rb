@@ -10,14 +10,29 @@ module KleinhirnLoader
       # The ID that was assigned to the worker by the supervisor process.
       WorkerID = new('KLEINHIRN_WORKER_ID')
 
+      # The worker's slot index (0...count), stable across replacements.
+      # A replacement that starts while the worker it replaces is still
+      # running gets an index past count.
+      WorkerIndex = new('KLEINHIRN_WORKER_INDEX')
+
       # Name of the supervision group, for appropriate process naming.
       Name = new('KLEINHIRN_NAME')
 
       # Version of the code that this supervision process group has loaded.
       Version = new('KLEINHIRN_VERSION')
 
+      # The token that the worker has to include in its ack.
+      AckToken = new('KLEINHIRN_ACK_TOKEN')
+
       # The status FD number.
       StatusFD = new('KLEINHIRN_STATUS_FD')
+
+      # The FD number that the worker reads supervisor requests from.
+      RequestsFD = new('KLEINHIRN_REQUESTS_FD')
+
+      # The protocol capabilities of the supervisor, comma-separated.
+      # Set by the supervisor, not the loader.
+      Capabilities = new('KLEINHIRN_CAPABILITIES')
     end
 
     # Sets the corresponding environment variable
@@ -8,6 +8,7 @@ require 'kleinhirn_loader/replies'
 
 require 'set'
 require 'json'
+require 'socket'
 
 module KleinhirnLoader
   # Logic for the actual preloading "server" process
@@ -24,6 +25,26 @@ module KleinhirnLoader
       @expression = expression
       @status_io = status_io
       @worker_ids = T.let(Set.new, T::Set[String])
+      @capabilities = T.let([], T::Array[String])
+      @request_ios = T.let({}, T::Hash[String, IO])
+    end
+
+    # Introduces the loader to the supervisor, and records which
+    # optional protocol features both sides support.
+    sig { void }
+    def hello
+      theirs = KleinhirnLoader::Env::Capabilities.env&.split(',') || []
+      KleinhirnLoader::Env::Capabilities.unset
+      @capabilities = KleinhirnLoader::CAPABILITIES & theirs
+      state_update(KleinhirnLoader::Replies::Hello.new(KleinhirnLoader::PROTOCOL_VERSION,
+                                                       KleinhirnLoader::CAPABILITIES))
+    end
+
+    # Returns true if both the supervisor and this loader support the
+    # protocol feature `capability`.
+    sig { params(capability: String).returns(T::Boolean) }
+    def supports?(capability)
+      @capabilities.include?(capability)
     end
 
     # Loads the input source file and, if successful, prints "ready".
@@ -61,9 +82,11 @@ module KleinhirnLoader
           if @worker_ids.include?(id)
             state_update(KleinhirnLoader::Replies::Failed.new(id, 'duplicate ID'))
           else
-            fork_one(id)
+            fork_one(id, command.index, command.expression || @expression, command.token)
             @worker_ids << id
           end
+        when KleinhirnLoader::Command::Forward
+          forward(command.id, command.request)
         when KleinhirnLoader::Command::Error
           state_update(KleinhirnLoader::Replies::Error.new('in command processing', command.error))
         else
@@ -113,19 +136,29 @@ module KleinhirnLoader
     #  * Close stdin
     #  * change working directory to `/`
     sig do
-      params(child_id: String)
+      params(child_id: String, index: T.nilable(Integer), token: T.nilable(String))
         .void
     end
-    def setup_child_environment(child_id)
+    def setup_child_environment(child_id, index, token)
       Dir.chdir('/')
       reseed_random
 
       KleinhirnLoader::Env::WorkerID.env = child_id
+      KleinhirnLoader::Env::WorkerIndex.env = index.to_s unless index.nil?
+      KleinhirnLoader::Env::AckToken.env = token unless token.nil?
       KleinhirnLoader::Env::Name.env = @name
       KleinhirnLoader::Env::Version.env = @version
       KleinhirnLoader::Env::StatusFD.env = @status_io.fileno.to_s
     end
 
+    # Points socket-activation's LISTEN_PID at the worker process, so
+    # that sd_listen_fds-aware code accepts the inherited listen
+    # sockets. LISTEN_FDS and LISTEN_FDNAMES are passed on unchanged.
+    sig { void }
+    def claim_listen_fds
+      ENV['LISTEN_PID'] = Process.pid.to_s if ENV.include?('LISTEN_FDS')
+    end
+
     # Updates the random seeds in ruby's various RNGs. This comes from
     # Einhorn, which has a good explanation in
     # https://github.com/stripe/einhorn/blob/8d90062ede64025e219949c6c63f061540d99f80/lib/einhorn/command.rb#L367-L395
@@ -160,34 +193,83 @@ module KleinhirnLoader
       T.unsafe(GC).compact if GC.respond_to?(:compact)
     end
 
-    # Double-forks one pre-loaded worker process. The direct child's
-    # PID is discarded, in expectation of getting re-parented to our
-    # supervisor process.
+    # Writes `request` to the socket of the worker `id`, without
+    # waiting for the worker to read it. Sockets of workers that are
+    # gone (or don't keep up) get closed.
+    sig { params(id: String, request: T::Hash[String, T.untyped]).void }
+    def forward(id, request)
+      prune_request_ios
+      unless (io = @request_ios[id])
+        log_info('no request socket for worker', worker_id: id)
+        return
+      end
+
+      io.write_nonblock("#{request.to_json}\n")
+    rescue SystemCallError, IOError => e
+      log_info('could not forward request to worker', worker_id: id, error: e.message)
+      @request_ios.delete(id)&.close
+    end
+
+    # Closes the request sockets of workers that have exited. Workers
+    # never write to their request socket, so it only becomes
+    # readable once they close it.
+    sig { void }
+    def prune_request_ios
+      @request_ios.reject! do |_, io|
+        next false unless io.read_nonblock(1, exception: false).nil?
+
+        io.close
+        true
+      end
+    end
+
+    # Double-forks one pre-loaded worker process that runs
+    # `expression`. The direct child's PID is discarded, in expectation
+    # of getting re-parented to our supervisor process. If the
+    # supervisor sends requests, the worker gets a socket that the
+    # loader forwards them to.
     sig do
-      params(child_id: String)
+      params(child_id: String, index: T.nilable(Integer), expression: String, token: T.nilable(String))
         .void
     end
-    def fork_one(child_id)
+    def fork_one(child_id, index, expression, token)
+      ours, theirs = supports?('requests') ? UNIXSocket.pair : [nil, nil]
       if (pid = Process.fork)
         # we're the initial parent - wait for the immediate child.
+        theirs&.close
         until pid == Process.waitpid(pid); end
-        state_update(KleinhirnLoader::Replies::Failed.new(child_id, 'non-zero exit')) unless $?.exitstatus.zero?
+        if $?.exitstatus.zero?
+          prune_request_ios
+          @request_ios[child_id] = ours if ours
+        else
+          ours&.close
+          state_update(KleinhirnLoader::Replies::Failed.new(child_id, 'non-zero exit'))
+        end
         return
       end
 
       # This is the first sub-child. Prepare our environment, fork
       # again, announce it and exit:
-      setup_child_environment(child_id)
+      ours&.close
+      @request_ios.each_value(&:close)
+      @request_ios.clear
+      if theirs
+        # The worker opens the FD again; it must not go away with the IO:
+        theirs.autoclose = false
+        KleinhirnLoader::Env::RequestsFD.env = theirs.fileno.to_s
+      end
+      setup_child_environment(child_id, index, token)
       if (pid = Process.fork)
         state_update(KleinhirnLoader::Replies::Launched.new(child_id, pid))
         exit(0)
       end
 
       # Now we're in the worker - start it up.
-      process_name = "#{@name}/#{@version} ::KleinhirnLoader::Worker #{child_id} - startup"
+      claim_listen_fds
+      process_name = "#{@name}/#{@version} ::KleinhirnLoader::Worker #{index} #{child_id} - startup"
       Process.setproctitle(process_name)
-      log_info('worker starting', child_id: child_id, pid: Process.pid.to_s)
-      eval(@expression, Empty.new.to_binding) # rubocop:disable Security/Eval
+      log_info('worker starting', child_id: child_id, worker_index: index.to_s, pid: Process.pid.to_s)
+      eval(expression, Empty.new.to_binding) # rubocop:disable Security/Eval
       exit(0)
     end
   end
@@ -19,6 +19,28 @@ module KleinhirnLoader
       def to_json(*args); end
     end
 
+    # Introduces the loader to the supervisor: Always the first reply,
+    # carrying the protocol version and the loader's capabilities.
+    class Hello < AbstractReply
+      sig do
+        params(protocol_version: Integer, capabilities: T::Array[String])
+          .void
+      end
+      def initialize(protocol_version, capabilities)
+        @protocol_version = protocol_version
+        @capabilities = capabilities
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        {
+          'action': 'hello',
+          'protocol_version': @protocol_version,
+          'capabilities': @capabilities,
+        }.to_json
+      end
+    end
+
     # Indicates that the loader is loading the given ruby file.
     class Loading < AbstractReply
       sig do
@@ -117,8 +139,105 @@ module KleinhirnLoader
     end
 
     # A worker process acknowledging to the supervisor that it is done
-    # initializing & ready to serve requests.
+    # initializing & ready to serve requests. It can report the
+    # version of the code it loaded, which the supervisor checks
+    # against the version it expects, and free-form build info. The
+    # `token` proves that the ack comes from the worker.
     class Ack < AbstractReply
+      sig do
+        params(id: String, token: T.nilable(String), version: T.nilable(String),
+               build_info: T.nilable(T::Hash[String, T.untyped]))
+          .void
+      end
+      def initialize(id, token: nil, version: nil, build_info: nil)
+        @id = id
+        @token = token
+        @version = version
+        @build_info = build_info
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        val = {
+          'action': 'ack',
+          'id': @id,
+        }
+        val['token'] = @token if @token
+        val['version'] = @version if @version
+        val['build_info'] = @build_info if @build_info
+        val.to_json
+      end
+    end
+
+    # A worker process asking the supervisor for more time to finish
+    # initializing: its ack deadline moves to `seconds` from now.
+    class ExtendTimeout < AbstractReply
+      sig do
+        params(id: String, seconds: Numeric)
+          .void
+      end
+      def initialize(id, seconds)
+        @id = id
+        @seconds = seconds
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        {
+          'action': 'extend_timeout',
+          'id': @id,
+          'by': "#{(@seconds * 1000).round}ms",
+        }.to_json
+      end
+    end
+
+    # A worker process telling the supervisor what it is busy with
+    # while it initializes.
+    class Progress < AbstractReply
+      sig do
+        params(id: String, message: String)
+          .void
+      end
+      def initialize(id, message)
+        @id = id
+        @message = message
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        {
+          'action': 'progress',
+          'id': @id,
+          'message': @message,
+        }.to_json
+      end
+    end
+
+    # A worker process telling the supervisor that it can't take work
+    # right now, and why.
+    class Unready < AbstractReply
+      sig do
+        params(id: String, reason: String)
+          .void
+      end
+      def initialize(id, reason)
+        @id = id
+        @reason = reason
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        {
+          'action': 'unready',
+          'id': @id,
+          'reason': @reason,
+        }.to_json
+      end
+    end
+
+    # A worker process telling the supervisor that it can take work
+    # again. (Not to be confused with the loader's `Ready`.)
+    class WorkerReady < AbstractReply
       sig do
         params(id: String)
           .void
@@ -130,8 +249,31 @@ module KleinhirnLoader
       sig { override.params(_args: T.untyped).returns(String) }
       def to_json(*_args)
         {
-          'action': 'ack',
+          'action': 'ready',
+          'id': @id,
+        }.to_json
+      end
+    end
+
+    # The worker's answer to a request from the supervisor.
+    class Reply < AbstractReply
+      sig do
+        params(id: String, request: Integer, reply: T.untyped)
+          .void
+      end
+      def initialize(id, request, reply)
+        @id = id
+        @request = request
+        @reply = reply
+      end
+
+      sig { override.params(_args: T.untyped).returns(String) }
+      def to_json(*_args)
+        {
+          'action': 'reply',
           'id': @id,
+          'request': @request,
+          'reply': @reply,
         }.to_json
       end
     end
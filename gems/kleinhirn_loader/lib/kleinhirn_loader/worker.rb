@@ -8,37 +8,130 @@ require 'kleinhirn_loader/replies'
 
 module KleinhirnLoader
   # Worker processes - confirmation that startup is finished and
-  # cleanup.
+  # cleanup. The worker can keep telling the supervisor whether it is
+  # ready to take work after it is done starting up, through the same
+  # instance.
   class Worker
     extend T::Sig
 
-    # Confirms to the supervisor that startup / initialization is done.
+    sig { void }
+    def initialize
+      @worker_id = T.let(KleinhirnLoader::Env::WorkerID.env, T.nilable(String))
+      @status_fd = T.let(KleinhirnLoader::Env::StatusFD.env&.to_i, T.nilable(Integer))
+      @requests_fd = T.let(KleinhirnLoader::Env::RequestsFD.env&.to_i, T.nilable(Integer))
+    end
+
+    # Confirms to the supervisor that startup / initialization is
+    # done. If given, `version` names the version of the code that the
+    # worker loaded (like the git SHA of the deployed tree); the
+    # supervisor refuses workers whose version differs from the one it
+    # expects. `build_info` gets logged.
     sig do
-      void
+      params(version: T.nilable(String), build_info: T.nilable(T::Hash[String, T.untyped]))
+        .void
     end
-    def done
-      if confirm_loaded
+    def done(version: nil, build_info: nil)
+      if confirm_loaded(version, build_info)
         cleanup!
       end
     end
 
+    # Asks the supervisor for `seconds` more time (counted from now)
+    # to finish initializing, up to its configured `max_ack_timeout`.
+    sig { params(seconds: Numeric).void }
+    def extend_timeout(seconds)
+      worker_id = @worker_id
+      return if worker_id.nil?
+
+      send_reply(KleinhirnLoader::Replies::ExtendTimeout.new(worker_id, seconds))
+    end
+
+    # Tells the supervisor what the worker is busy with while it
+    # initializes; it shows up in the supervisor's status.
+    sig { params(message: String).void }
+    def progress(message)
+      worker_id = @worker_id
+      return if worker_id.nil?
+
+      send_reply(KleinhirnLoader::Replies::Progress.new(worker_id, message))
+    end
+
+    # Takes the worker out of rotation without stopping it, say
+    # because it lost its database connection: The supervisor counts
+    # it as not ready, for `reason`, until it calls `ready`.
+    sig { params(reason: String).void }
+    def unready(reason)
+      worker_id = @worker_id
+      return if worker_id.nil?
+
+      send_reply(KleinhirnLoader::Replies::Unready.new(worker_id, reason))
+    end
+
+    # Puts the worker back into rotation after `unready`.
+    sig { void }
+    def ready
+      worker_id = @worker_id
+      return if worker_id.nil?
+
+      send_reply(KleinhirnLoader::Replies::WorkerReady.new(worker_id))
+    end
+
+    # Yields each request that the supervisor sends (a Hash with the
+    # `request` number, the `op` - `drain`, `reopen_logs`,
+    # `dump_status` or `custom` - and the `command` of custom ones),
+    # and answers it with the block's result unless that is nil.
+    # Returns once the supervisor (or the preloader that forwards
+    # requests) closes the channel, or right away if the worker gets
+    # no requests.
+    sig { params(blk: T.proc.params(request: T::Hash[String, T.untyped]).returns(T.untyped)).void }
+    def each_request(&blk)
+      worker_id = @worker_id
+      fd = @requests_fd
+      return if worker_id.nil? || fd.nil?
+
+      requests_io = IO.new(fd, autoclose: false)
+      requests_io.close_on_exec = true
+      requests_io.each_line do |line|
+        request = JSON.parse(line)
+        reply = blk.call(request)
+        next if reply.nil?
+
+        send_reply(KleinhirnLoader::Replies::Reply.new(worker_id, request['request'], reply))
+      end
+    end
+
     private
 
+    # Sends `reply` on the status FD, keeping it open (but out of
+    # reach of programs that the worker runs).
+    sig { params(reply: KleinhirnLoader::Replies::AbstractReply).returns(T::Boolean) }
+    def send_reply(reply)
+      fd = @status_fd
+      return false if fd.nil?
+
+      status_io = IO.new(fd, autoclose: false)
+      status_io.close_on_exec = true
+      status_io.puts(reply.to_json)
+      status_io.flush
+      true
+    end
+
     sig do
-      returns(T::Boolean)
+      params(version: T.nilable(String), build_info: T.nilable(T::Hash[String, T.untyped]))
+        .returns(T::Boolean)
     end
-    def confirm_loaded
-      fd = KleinhirnLoader::Env::StatusFD.env&.to_i
-      worker_id = KleinhirnLoader::Env::WorkerID.env
+    def confirm_loaded(version, build_info)
+      worker_id = @worker_id
+      index = KleinhirnLoader::Env::WorkerIndex.env
       name = KleinhirnLoader::Env::Name.env
-      version = KleinhirnLoader::Env::Version.env
-      return false if fd.nil? || worker_id.nil?
+      loader_version = KleinhirnLoader::Env::Version.env
+      return false if worker_id.nil?
 
-      status_io = IO.new(fd)
-      status_io.puts(KleinhirnLoader::Replies::Ack.new(worker_id).to_json)
-      status_io.close
+      token = KleinhirnLoader::Env::AckToken.env
+      ack = KleinhirnLoader::Replies::Ack.new(worker_id, token: token, version: version, build_info: build_info)
+      return false unless send_reply(ack)
 
-      process_name = "#{name}/#{version} ::KleinhirnLoader::Worker #{worker_id}"
+      process_name = "#{name}/#{version || loader_version} ::KleinhirnLoader::Worker #{index} #{worker_id}"
       Process.setproctitle(process_name)
       true
     end
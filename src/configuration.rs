@@ -1,16 +1,21 @@
+use crate::worker_ack::WorkerOp;
+use anyhow::{bail, Context};
 use futures::stream::{pending, Stream};
 use futures_ticker::Ticker;
-use serde::Deserialize;
+use nix::sys::signal::Signal;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Deserializer};
 use std::{
     collections::HashMap,
     net::SocketAddr,
     path::{Path, PathBuf},
+    str::FromStr,
     time::{Duration, Instant},
 };
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Config {
     #[serde(default)]
     pub health_check: HealthConfig,
@@ -18,13 +23,34 @@ pub struct Config {
     #[serde(default)]
     pub log: LoggingConfig,
     pub supervisor: SupervisorConfig,
-    pub worker: WorkerConfig,
+
+    /// A single group of workers, named after the supervised service.
+    #[serde(default)]
+    pub worker: Option<WorkerConfig>,
+
+    /// Groups of workers that get supervised alongside each other.
+    #[serde(default)]
+    #[serde(rename = "worker_group")]
+    pub worker_groups: Vec<WorkerGroup>,
 
     #[serde(skip)]
     pub base_dir: PathBuf,
 }
 
 impl Config {
+    /// Returns all configured worker groups: The `[worker]` section
+    /// (if any), followed by all `[[worker_group]]` sections.
+    pub fn all_worker_groups(&self) -> Vec<WorkerGroup> {
+        self.worker
+            .iter()
+            .map(|worker| WorkerGroup {
+                name: self.supervisor.name.to_string(),
+                worker: worker.clone(),
+            })
+            .chain(self.worker_groups.iter().cloned())
+            .collect()
+    }
+
     #[allow(dead_code)] // TODO: use this more consistently
     pub(crate) fn canonical_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         self.base_dir.join(path)
@@ -141,11 +167,116 @@ pub struct SupervisorConfig {
     /// Also creates a lock file next to the socket, in order to prevent multiple uncontrollable
     /// copies from being created.
     pub socket: Option<PathBuf>,
+
+    /// What to do when the worker set is faulted. Default: `stay`
+    #[serde(default)]
+    pub on_fault: FaultPolicy,
+
+    /// The version of the code that the service runs, like a git
+    /// SHA. It can refer to `${env:VAR}` and `${file:PATH}` (see
+    /// [`template::expand_version`](crate::template::expand_version)).
+    /// Workers get it as `$KLEINHIRN_VERSION`, and it shows up in
+    /// logs and status. Default: none
+    #[serde(default)]
+    pub version: Option<String>,
+
+    /// Requests to send to all workers when the supervisor receives a
+    /// signal, by signal name (like `HUP`). A request is `drain`,
+    /// `reopen_logs`, `dump_status`, or a custom command that only the
+    /// workers understand. Workers read them off the FD in
+    /// `$KLEINHIRN_REQUESTS_FD`, which only workers that ack on their
+    /// status FD and preloaded workers get. Default: `{ HUP = "reopen_logs", USR1 = "dump_status" }`
+    #[serde(default = "default_worker_signals")]
+    pub worker_signals: HashMap<String, String>,
+}
+
+impl SupervisorConfig {
+    /// Returns the signals in [`worker_signals`](Self::worker_signals)
+    /// and the request that each of them sends, sorted by signal.
+    pub fn worker_signals(&self) -> anyhow::Result<Vec<(Signal, WorkerOp)>> {
+        let mut signals = self
+            .worker_signals
+            .iter()
+            .map(|(name, request)| {
+                let name = name.to_uppercase();
+                let name = if name.starts_with("SIG") {
+                    name
+                } else {
+                    format!("SIG{}", name)
+                };
+                let signal = Signal::from_str(&name)
+                    .with_context(|| format!("Unknown signal {:?} in worker_signals", name))?;
+                match signal {
+                    Signal::SIGTERM | Signal::SIGINT | Signal::SIGCHLD => {
+                        bail!("{} is used by the supervisor itself", name)
+                    }
+                    _ => Ok((signal, WorkerOp::named(request))),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        signals.sort_by_key(|(signal, _)| *signal as i32);
+        Ok(signals)
+    }
+}
+
+fn default_worker_signals() -> HashMap<String, String> {
+    vec![("HUP", "reopen_logs"), ("USR1", "dump_status")]
+        .into_iter()
+        .map(|(signal, request)| (signal.to_string(), request.to_string()))
+        .collect()
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "policy")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FaultPolicy {
+    /// Keep running (and reaping children), reporting the worker set
+    /// as unhealthy until the supervisor gets killed externally.
+    Stay,
+
+    /// Exit the supervisor with the given exit code, so that an
+    /// orchestrator can restart it. Default code: 70
+    Exit {
+        #[serde(default = "default_fault_exit_code")]
+        code: i32,
+    },
+
+    /// Wait for the cooldown to pass, then reset all failure counters
+    /// and start launching workers again, keeping the ones that are
+    /// still running. A worker set whose preloader died stays
+    /// faulted, since nothing could launch new workers.
+    Recover {
+        #[serde(with = "humantime_serde")]
+        cooldown: Duration,
+    },
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        FaultPolicy::Stay
+    }
+}
+
+fn default_fault_exit_code() -> i32 {
+    70
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct WorkerGroup {
+    /// Name of the group, used in logs and health checks. Must be
+    /// unique.
+    pub name: String,
+
+    #[serde(flatten)]
+    pub worker: WorkerConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Clone)]
 pub struct WorkerConfig {
     /// Number of workers to spawn. Default: 1
     #[serde(default = "default_count")]
@@ -157,32 +288,269 @@ pub struct WorkerConfig {
     #[serde(default)]
     #[serde(with = "humantime_serde")]
     pub ack_timeout: Option<Duration>,
+
+    /// The latest, counted from launch, that a worker may push its
+    /// ack deadline to by asking for more time (with an
+    /// `extend_timeout` message, or `EXTEND_TIMEOUT_USEC=` on the
+    /// notification socket). Default: no extensions
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub max_ack_timeout: Option<Duration>,
+
+    /// The time a worker that is about to be stopped gets to finish
+    /// its work. If set, workers that can receive
+    /// [requests](SupervisorConfig::worker_signals) are asked to
+    /// `drain` first, and only get SIGTERM once this time has
+    /// passed. Default: workers get SIGTERM right away
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Option<Duration>,
+
+    /// The time a worker may take between being requested and being
+    /// reported as launched. Workers that take longer are treated as
+    /// having failed to launch. Default: unlimited
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub launch_timeout: Option<Duration>,
+
+    /// The number of consecutive launch failures after which the
+    /// worker set is marked as faulted. Default: 3
+    #[serde(default = "default_max_launch_failures")]
+    pub max_launch_failures: usize,
+
+    /// The time to wait before launching a worker again after a
+    /// launch failed. Default: 1s
+    #[serde(default = "default_launch_retry_delay")]
+    #[serde(with = "humantime_serde")]
+    pub launch_retry_delay: Duration,
+
+    /// What to do with workers that don't ack within
+    /// [`WorkerConfig.ack_timeout`]. Default: `fault`
+    #[serde(default)]
+    pub on_ack_timeout: AckTimeoutPolicy,
+
+    /// The number of failed workers (e.g. ones that timed out
+    /// waiting for an ack) that get replaced before the worker set
+    /// is marked as faulted. The count starts over once all workers
+    /// have acked again. Default: 3
+    #[serde(default = "default_failure_budget")]
+    pub failure_budget: usize,
+
+    /// The maximum amount of time that a worker may run after it
+    /// acked. Workers that have been running longer than this get
+    /// replaced: A new worker is launched first, and the old one is
+    /// killed once the replacement has acked. Default: unlimited
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub max_lifetime: Option<Duration>,
+
+    /// The fraction (between 0.0 and 1.0) of [`WorkerConfig.max_lifetime`]
+    /// by which each worker's lifetime is randomly shortened, so that
+    /// workers started at the same time don't all get recycled at the
+    /// same moment. Default: 0.0
+    #[serde(default)]
+    pub lifetime_jitter: f64,
 }
 
+/// The coarsest interval at which worker lifetimes get checked.
+const LIFETIME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 impl WorkerConfig {
-    pub fn ack_ticker(&self) -> Box<dyn Stream<Item = Instant> + Unpin> {
-        if let Some(timeout) = self.ack_timeout {
-            Box::new(Ticker::new(timeout / 2))
+    /// Returns a stream of timer ticks, at an interval that allows
+    /// checking all configured worker timeouts in time.
+    pub fn ticker(&self) -> Box<dyn Stream<Item = Instant> + Unpin> {
+        let intervals = [
+            self.ack_timeout.map(|timeout| timeout / 2),
+            self.launch_timeout.map(|timeout| timeout / 2),
+            Some(self.launch_retry_delay / 2),
+            self.max_lifetime
+                .map(|lifetime| (lifetime / 2).min(LIFETIME_CHECK_INTERVAL)),
+        ];
+        if let Some(&interval) = intervals.iter().flatten().min() {
+            Box::new(Ticker::new(interval))
         } else {
             Box::new(pending())
         }
     }
+
+    /// Returns the time a newly-acked worker may run before it gets
+    /// replaced, shortened by a random amount of up to
+    /// [`WorkerConfig.lifetime_jitter`].
+    pub(crate) fn worker_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime.map(|lifetime| {
+            let jitter = self.lifetime_jitter.clamp(0.0, 1.0);
+            lifetime - lifetime.mul_f64(jitter * thread_rng().gen::<f64>())
+        })
+    }
 }
 
 fn default_count() -> usize {
     1
 }
 
+fn default_failure_budget() -> usize {
+    3
+}
+
+fn default_max_launch_failures() -> usize {
+    3
+}
+
+fn default_launch_retry_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AckTimeoutPolicy {
+    /// Mark the entire worker set as faulted.
+    Fault,
+
+    /// Kill the worker that timed out and launch a replacement,
+    /// counting the timeout against the
+    /// [`WorkerConfig.failure_budget`].
+    Replace,
+}
+
+impl Default for AckTimeoutPolicy {
+    fn default() -> Self {
+        AckTimeoutPolicy::Fault
+    }
+}
+
+/// How the supervisor learns that a program's worker is ready, which
+/// is when it counts as acked. Probes are retried every `interval`
+/// until they succeed or the worker's `ack_timeout` passes.
+#[derive(Deserialize)]
+#[serde(tag = "method")]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AckStrategy {
+    /// The worker counts as acked as soon as it is spawned.
+    Immediate,
+
+    /// The worker sends an ack message on the FD named in
+    /// `$KLEINHIRN_STATUS_FD`, like
+    /// `{"action":"ack","id":"$KLEINHIRN_WORKER_ID","token":"$KLEINHIRN_ACK_TOKEN"}`.
+    /// Acks without the token, or (on Linux) sent by any process but
+    /// the worker itself, don't count. The supervisor sends
+    /// [requests](crate::worker_ack::WorkerRequest) back on the same
+    /// FD (which is also in `$KLEINHIRN_REQUESTS_FD`), one JSON object
+    /// per line.
+    StatusFd,
+
+    /// The worker gets a `NOTIFY_SOCKET` and counts as acked once it
+    /// sends `READY=1`, as it would under systemd. Only supported on
+    /// Linux.
+    Notify,
+
+    /// Probe whether a TCP connection to `host`:`port` succeeds. Both
+    /// support variable substitution (so `port` is a string).
+    Tcp {
+        /// Default: "127.0.0.1"
+        #[serde(default = "default_probe_host")]
+        host: String,
+
+        port: String,
+
+        /// Default: 100ms
+        #[serde(default = "default_probe_interval", with = "humantime_serde")]
+        interval: Duration,
+    },
+
+    /// Probe whether a GET request to the `http://` URL `url` returns
+    /// a 2xx status. The URL supports variable substitution. An
+    /// attempt that gets no answer within 5s counts as failed.
+    Http {
+        url: String,
+
+        /// Default: 100ms
+        #[serde(default = "default_probe_interval", with = "humantime_serde")]
+        interval: Duration,
+    },
+
+    /// Probe whether a file exists at `path`, which supports variable
+    /// substitution.
+    File {
+        path: PathBuf,
+
+        /// Default: 100ms
+        #[serde(default = "default_probe_interval", with = "humantime_serde")]
+        interval: Duration,
+    },
+
+    /// The worker counts as acked once `delay` has passed since it
+    /// was spawned.
+    Delay {
+        #[serde(with = "humantime_serde")]
+        delay: Duration,
+    },
+}
+
+impl Default for AckStrategy {
+    fn default() -> Self {
+        AckStrategy::Immediate
+    }
+}
+
+/// Deserializes an [`AckStrategy`], or the boolean that the
+/// `ack_workers` setting used to take.
+fn ack_strategy<'de, D>(deserializer: D) -> Result<AckStrategy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StrategyOrBool {
+        Bool(bool),
+        Strategy(AckStrategy),
+    }
+
+    Ok(match StrategyOrBool::deserialize(deserializer)? {
+        StrategyOrBool::Bool(true) => AckStrategy::StatusFd,
+        StrategyOrBool::Bool(false) => AckStrategy::Immediate,
+        StrategyOrBool::Strategy(strategy) => strategy,
+    })
+}
+
+fn default_probe_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_probe_interval() -> Duration {
+    Duration::from_millis(100)
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct Program {
+    /// The command line to run. Supports variable substitution, see
+    /// [`crate::template`].
     pub cmdline: Vec<String>,
+
+    /// Environment variables to set on the worker. The values support
+    /// variable substitution.
     pub env: HashMap<String, String>,
+
+    /// The directory to run the worker in. Supports variable
+    /// substitution.
     pub cwd: Option<PathBuf>,
 
+    /// How to tell that a worker is ready. The old `ack_workers`
+    /// setting is still understood: `true` means `status_fd`, `false`
+    /// means `immediate`. Default: immediate
+    #[serde(default, alias = "ack_workers", deserialize_with = "ack_strategy")]
+    pub ack: AckStrategy,
+
+    /// If true, the elements of `cmdline` are joined with spaces and
+    /// run via `/bin/sh -c`. Variables get substituted before the
+    /// shell sees the command line, so the shell's `$$` has to be
+    /// written as `$$$$`, and `${VAR}` as `$${VAR}`; `$VAR` works as
+    /// is. Default: false
     #[serde(default)]
-    pub ack_workers: bool,
+    pub shell: bool,
 }
 
 #[derive(Deserialize)]
@@ -190,14 +558,121 @@ pub struct Program {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Ruby {
     /// The pathname identifying the Gemfile for the bundled ruby
-    /// application.
-    pub gemfile: PathBuf,
+    /// application. The preloader runs via `bundle exec`. Without a
+    /// Gemfile, `kleinhirn_loader` is run directly, and has to be on
+    /// the `PATH`. Default: none
+    #[serde(default)]
+    pub gemfile: Option<PathBuf>,
 
-    /// A ruby file that can be `load`ed.
-    pub load: PathBuf,
+    /// The ruby files that the preloader `load`s, in order: Either
+    /// one path or a list of them.
+    #[serde(deserialize_with = "one_or_many")]
+    pub load: Vec<PathBuf>,
 
     /// A ruby expression that each worker runs in order to start.
     pub start_expression: String,
+
+    /// Flags for the ruby interpreter, like `["-W0",
+    /// "--jit"]`. They get appended to `$RUBYOPT`. Default: none
+    #[serde(default)]
+    pub ruby_flags: Vec<String>,
+
+    /// Environment variables to set on the preloader, and so on all
+    /// workers. Default: none
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// The directory to run the preloader in, relative to the
+    /// configuration file. Default: the supervisor's directory
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+
+    /// The time the preloader may take to load all code and get
+    /// ready. A preloader that takes longer gets killed. Default:
+    /// unlimited
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub boot_timeout: Option<Duration>,
+
+    /// The time the preloader may take to load any single file. A
+    /// preloader that takes longer gets killed, and the file it was
+    /// stuck on gets logged. Default: unlimited
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub load_timeout: Option<Duration>,
+}
+
+/// Deserializes either a single value or a list of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Python {
+    /// The module that the preloader imports. The preloader runs in
+    /// the directory of the configuration file, so modules relative
+    /// to it can be imported.
+    pub module: String,
+
+    /// The name of a function in `module` that each worker calls in
+    /// order to start.
+    pub start_callable: String,
+
+    /// The python interpreter to run the preloader with. Default:
+    /// "python3"
+    #[serde(default = "default_python")]
+    pub python: PathBuf,
+}
+
+fn default_python() -> PathBuf {
+    PathBuf::from("python3")
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PreloaderProgram {
+    /// The command line that starts the preloader.
+    pub cmdline: Vec<String>,
+
+    /// Environment variables to set on the preloader.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// The directory to run the preloader in, relative to the
+    /// configuration file.
+    pub cwd: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SharedPreloader {
+    /// The name of the worker group whose preloader forks this
+    /// group's workers. It must be a `ruby`, `python` or `preloader`
+    /// group that is defined before this one.
+    pub group: String,
+
+    /// What each worker in this group runs in order to start: a ruby
+    /// expression for `ruby` groups, the name of a function for
+    /// `python` groups.
+    pub start_expression: String,
 }
 
 #[derive(Deserialize)]
@@ -205,19 +680,53 @@ pub struct Ruby {
 #[serde(rename_all = "snake_case")]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum WorkerKind {
-    /// Supervise a program that gets forked & exec'ed [`WorkerConfig.count`] times. This does
-    /// not support any variable substitution
-    /// or shell expansion.
+    /// Supervise a program that gets forked & exec'ed [`WorkerConfig.count`] times. The
+    /// command line, environment and working directory support
+    /// variable substitution (see [`crate::template`]); shell
+    /// expansion only happens if [`Program.shell`] is set.
     Program(Program),
 
-    /// Supervise a bundled ruby program that can be preloaded. That
-    /// bundle is expected to include the `kleinhirn_loader` gem,
-    /// which is then launched via the following expression:
+    /// Supervise a ruby program that can be preloaded. The
+    /// `kleinhirn_loader` gem is launched via the following
+    /// expression, in [`Ruby.cwd`] and with [`Ruby.env`] set; the
+    /// `bundle exec` part only applies if a [`Ruby.gemfile`] is
+    /// configured, otherwise `kleinhirn_loader` has to be on the
+    /// `PATH`:
     ///
     /// ```sh
-    /// bundle exec --gemfile=<gemfile_path> --keep-file-descriptors \
-    ///             kleinhirn_loader -- <load> <start_expression>
+    /// RUBYOPT="$RUBYOPT <ruby_flags>" \
+    /// bundle exec --gemfile <gemfile> --keep-file-descriptors -- \
+    ///     kleinhirn_loader --status-fd <fd> --name <name> -e <start_expression> \
+    ///                      [--code-version <version>] -r <load> [-r <load>...]
     /// ```
     #[cfg(target_os = "linux")]
     Ruby(Ruby),
+
+    /// Supervise a python program that gets preloaded by importing
+    /// `module`, using the preloader script that is bundled with
+    /// kleinhirn:
+    ///
+    /// ```sh
+    /// <python> -c <script> --module <module> --start-callable <start_callable>
+    /// ```
+    ///
+    /// Workers confirm that they started up with `import kleinhirn;
+    /// kleinhirn.done()`.
+    #[cfg(target_os = "linux")]
+    Python(Python),
+
+    /// Supervise workers that are forked by a preloader program
+    /// written in any language. The program gets the FD of its control
+    /// channel in `$KLEINHIRN_STATUS_FD`, and speaks the same JSONL
+    /// protocol as the ruby `kleinhirn_loader` on it, starting with a
+    /// `hello` message. The supervisor's capabilities are in
+    /// `$KLEINHIRN_CAPABILITIES`.
+    #[cfg(target_os = "linux")]
+    Preloader(PreloaderProgram),
+
+    /// Fork workers off the preloader of another worker group, but
+    /// start them with a different expression. Both groups then share
+    /// one copy of the preloaded code.
+    #[cfg(target_os = "linux")]
+    SharedPreloader(SharedPreloader),
 }
@@ -1,26 +1,59 @@
-use crate::configuration;
+use crate::configuration::{self, AckStrategy};
 use crate::{
-    process_control::{Message, ProcessControl},
-    worker_ack,
+    probe::Probe,
+    process_control::{Message, OnLoading, ProcessControl},
+    sd_notify::{self, NOTIFY_SOCKET_ENV},
+    socket_activation::ListenFds,
+    template, worker_ack, LogScoped,
 };
 use anyhow::{bail, Context, Result};
-use async_channel::{bounded, Receiver, Sender};
+use async_channel::{unbounded, Receiver, Sender};
 use async_trait::async_trait;
-use futures::io::AsyncBufReadExt;
-use slog_scope::debug;
+use futures::future::{BoxFuture, FutureExt};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use parking_lot::Mutex;
+use slog_scope::{debug, warn};
+use smol::Task;
 use std::env::current_dir;
-use std::{collections::HashMap, process::Command};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use worker_ack::WorkerControlMessage;
+use worker_ack::{AckDetails, WorkerControlMessage, WorkerOp, WorkerRequest};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug)]
 enum Action {
     Fork(String, u32),
-    Ack(String),
+    Ack(String, AckDetails, Option<u32>),
+    ExtendTimeout(String, Duration, Option<u32>),
+    Unready(String, String, Option<u32>),
+    Ready(String, Option<u32>),
+    Status(String, String),
+    Reply(String, u64, serde_json::Value),
+    Alive(String),
+    Failed(String, u32, anyhow::Error),
 }
 
+/// The IDs of workers that were given a notification socket, by PID.
+type NotifyingWorkers = Arc<Mutex<HashMap<u32, String>>>;
+
+/// The control channels of running workers with a status_fd, by ID.
+type RequestChannels = Arc<Mutex<HashMap<String, worker_ack::ControlChannel>>>;
+
 pub struct ForkExec {
+    name: String,
+    version: Option<String>,
     program: configuration::Program,
+    listen_fds: Option<ListenFds>,
+    notify_socket: Option<(String, NotifyingWorkers)>,
+    ack_timeout: Option<Duration>,
+    request_channels: RequestChannels,
+    last_request: u64,
     sender: Sender<Action>,
     receiver: Receiver<Action>,
 }
@@ -30,88 +63,162 @@ pub struct ForkExec {
 pub struct WorkerDied;
 
 impl ForkExec {
-    pub fn for_program(p: &configuration::Program) -> Result<ForkExec> {
+    pub fn for_program(
+        name: &str,
+        version: Option<&str>,
+        p: &configuration::Program,
+        listen_fds: Option<ListenFds>,
+        ack_timeout: Option<Duration>,
+    ) -> Result<ForkExec> {
         // TODO: do some error checking - validate that the program can be found and such?
-        let (sender, receiver) = bounded(20);
+        // Unbounded, so spawning (which queues the fork) never waits on
+        // worker traffic that only the supervise loop drains:
+        let (sender, receiver) = unbounded();
+        let notify_socket = if let AckStrategy::Notify = p.ack {
+            Some(listen_for_notifications(sender.clone())?)
+        } else {
+            None
+        };
         Ok(ForkExec {
+            name: name.to_string(),
+            version: version.map(str::to_string),
             program: p.clone(),
+            listen_fds,
+            notify_socket,
+            ack_timeout,
+            request_channels: Default::default(),
+            last_request: 0,
             sender,
             receiver,
         })
     }
-
-    /// Awaits the next message on the control channel and, if an Ack
-    /// message is received, returns the acked ID.
-    async fn receive_control_channel_ack(
-        &self,
-        mut control_channel: worker_ack::ControlChannel,
-    ) -> Result<String> {
-        let mut line = String::new();
-        let count = control_channel.read_line(&mut line).await?;
-        if count == 0 {
-            // Preloader has closed the connection. We assume it's dead.
-            debug!("read 0 bytes off the worker control channel, it's dead");
-            return Err(WorkerDied.into());
-        }
-        let ack_msg: WorkerControlMessage = serde_json::from_str(&line)?;
-        match ack_msg {
-            WorkerControlMessage::Ack { id } => Ok(id),
-        }
-    }
 }
 
 #[async_trait]
 impl ProcessControl for ForkExec {
-    async fn initialize(&mut self) -> Result<()> {
+    async fn initialize(&mut self, _on_loading: OnLoading<'_>) -> Result<()> {
         // No preparation necessary - we're ready to launch immediately.
         Ok(())
     }
-    async fn spawn_process(&mut self) -> Result<String> {
+    async fn spawn_process(&mut self, index: usize) -> Result<String> {
         let id = self.generate_id();
-        let mut cmdline = self.program.cmdline.iter();
+        let token = self.generate_token();
+        let vars = template::Variables {
+            worker_id: &id,
+            worker_index: index,
+            name: &self.name,
+            version: self.version.as_deref(),
+        };
+        let cmdline = self
+            .program
+            .cmdline
+            .iter()
+            .map(|arg| vars.expand(arg))
+            .collect::<Result<Vec<String>, _>>()
+            .context("Expanding the command line")?;
+        let env = self
+            .program
+            .env
+            .iter()
+            .map(|(k, v)| Ok((k, vars.expand(v)?)))
+            .collect::<Result<HashMap<&String, String>, template::TemplateError>>()
+            .context("Expanding the environment")?;
+        let cwd = match &self.program.cwd {
+            Some(cwd) => PathBuf::from(
+                vars.expand(&cwd.to_string_lossy())
+                    .context("Expanding the working directory")?,
+            ),
+            None => current_dir().context("No current working directory")?,
+        };
         let mut kleinhirn_vars: HashMap<&str, String> = HashMap::new();
         kleinhirn_vars.insert(WORKER_ID_ENV, id.to_string());
-        kleinhirn_vars.insert(NAME_ENV, self.program.cmdline.join(" "));
-        let worker_control = if self.program.ack_workers {
+        kleinhirn_vars.insert(ACK_TOKEN_ENV, token.to_string());
+        kleinhirn_vars.insert(WORKER_INDEX_ENV, index.to_string());
+        kleinhirn_vars.insert(NAME_ENV, self.name.to_string());
+        if let Some(version) = &self.version {
+            kleinhirn_vars.insert(VERSION_ENV, version.to_string());
+        }
+        if let Some((address, _)) = &self.notify_socket {
+            kleinhirn_vars.insert(NOTIFY_SOCKET_ENV, address.to_string());
+        }
+        let probe =
+            Probe::for_strategy(&self.program.ack, &vars).context("Setting up the ack probe")?;
+        let worker_control = if let AckStrategy::StatusFd = self.program.ack {
             let (their_fd, control_channel) = worker_ack::worker_status_stream()?;
             kleinhirn_vars.insert(WORKER_CONTROL_CHANNEL_ENV, their_fd.to_string());
-            // kleinhirn_vars.insert(WORKER_VERSION, ) // TODO: configure/pass in the version string.
+            kleinhirn_vars.insert(REQUESTS_FD_ENV, their_fd.to_string());
             Some((their_fd, control_channel))
         } else {
             None
         };
-        let child = Command::new(cmdline.next().expect("no commandline given").clone())
-            .args(cmdline)
-            .envs(self.program.env.iter())
+        let argv = if self.program.shell {
+            vec![SHELL.to_string(), "-c".to_string(), cmdline.join(" ")]
+        } else {
+            cmdline
+        };
+        let mut cmd = match &self.listen_fds {
+            Some(listen_fds) => listen_fds.command(SHELL, &argv),
+            None => {
+                let mut args = argv.iter();
+                let mut cmd = Command::new(args.next().context("no commandline given")?);
+                cmd.args(args);
+                cmd
+            }
+        };
+        let child = cmd
+            .envs(env)
             .envs(kleinhirn_vars)
-            .current_dir(
-                self.program
-                    .cwd
-                    .as_ref()
-                    .cloned()
-                    .unwrap_or_else(|| current_dir().expect("No current working directory")),
-            )
+            .current_dir(cwd)
             .spawn()
             .context("Spawning a worker")?;
 
+        if let Some((_, workers)) = &self.notify_socket {
+            let mut workers = workers.lock();
+            // Forget about workers that are gone:
+            workers.retain(|&pid, _| kill(Pid::from_raw(pid as i32), None).is_ok());
+            workers.insert(child.id(), id.to_string());
+        }
         self.sender
             .send(Action::Fork(id.to_string(), child.id()))
             .await?;
-        if let Some((_, control_channel)) = worker_control {
-            let acked_id = self
-                .receive_control_channel_ack(control_channel)
-                .await
-                .context("Receiving an ack from the control channel")?;
-            if acked_id != id {
-                bail!(
-                    "Received ack for ID {:?}, but expected {:?}",
-                    &acked_id,
-                    &id
-                );
-            }
-            self.sender.send(Action::Ack(acked_id)).await?;
+        if let Some((probe, interval)) = probe {
+            let deadline = self.ack_timeout.map(|timeout| Instant::now() + timeout);
+            let sender = self.sender.clone();
+            let (id, pid) = (id.to_string(), child.id());
+            Task::spawn(LogScoped::new(slog_scope::logger(), async move {
+                if probe.wait(interval, pid, deadline).await {
+                    debug!("worker is ready"; "worker_id" => &id, "probe" => ?probe);
+                    // The receiving end only goes away when we exit:
+                    let _ = sender
+                        .send(Action::Ack(id, AckDetails::default(), None))
+                        .await;
+                }
+            }))
+            .detach();
+        } else if let AckStrategy::Notify = self.program.ack {
+            // The ack arrives on the notification socket.
+        } else if let Some((_, control_channel)) = worker_control {
+            let channels = self.request_channels.clone();
+            channels
+                .lock()
+                .insert(id.to_string(), control_channel.clone());
+            let reading = read_control_channel(
+                control_channel,
+                id.to_string(),
+                child.id(),
+                token,
+                self.sender.clone(),
+            );
+            let id = id.to_string();
+            Task::spawn(LogScoped::new(slog_scope::logger(), async move {
+                reading.await;
+                channels.lock().remove(&id);
+            }))
+            .detach();
         } else {
-            self.sender.send(Action::Ack(id.to_string())).await?;
+            self.sender
+                .send(Action::Ack(id.to_string(), AckDetails::default(), None))
+                .await?;
         }
         Ok(id)
     }
@@ -124,24 +231,220 @@ impl ProcessControl for ForkExec {
             .context("fork_exec control channel got closed for some reason?")?
         {
             Action::Fork(id, pid) => Ok(Message::Launched { id, pid }),
-            Action::Ack(id) => Ok(Message::Ack { id }),
+            Action::Ack(id, details, sender) => Ok(Message::Ack {
+                id,
+                details,
+                sender,
+            }),
+            Action::ExtendTimeout(id, by, sender) => Ok(Message::ExtendTimeout { id, by, sender }),
+            Action::Unready(id, reason, sender) => Ok(Message::Unready { id, reason, sender }),
+            Action::Ready(id, sender) => Ok(Message::Ready { id, sender }),
+            Action::Status(id, status) => Ok(Message::Status { id, status }),
+            Action::Reply(id, request, reply) => Ok(Message::Reply { id, request, reply }),
+            Action::Alive(id) => Ok(Message::Alive { id }),
+            Action::Failed(id, pid, error) => Ok(Message::LaunchError {
+                id,
+                pid: Some(pid),
+                error,
+            }),
+        }
+    }
+
+    fn send_to_worker(&mut self, id: &str, op: WorkerOp) -> Result<BoxFuture<'static, Result<()>>> {
+        let channel = match self.request_channels.lock().get(id) {
+            Some(channel) => channel.clone(),
+            None => bail!("Worker {} has no open control channel", id),
+        };
+        self.last_request += 1;
+        let request = WorkerRequest {
+            request: self.last_request,
+            op,
+        };
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        debug!("sending a request to the worker"; "worker_id" => id, "request" => ?request);
+        Ok(async move {
+            channel
+                .send(&line)
+                .await
+                .context("Sending a request on the control channel")
+        }
+        .boxed())
+    }
+}
+
+/// Reads the messages that the worker `id` with PID `pid` sends on
+/// its control channel and translates them into actions, until the
+/// worker closes the channel. Until the worker has acked, anything
+/// unexpected on the channel counts as a failed launch.
+async fn read_control_channel(
+    control_channel: worker_ack::ControlChannel,
+    id: String,
+    pid: u32,
+    token: String,
+    sender: Sender<Action>,
+) {
+    let mut lines = control_channel.lines();
+    let mut acked = false;
+    loop {
+        let result = match lines.next_line().await {
+            Ok(Some((line, from))) => control_action(&line, &id, &token, from),
+            Ok(None) if acked => return,
+            Ok(None) => {
+                // Worker has closed the connection. We assume it's dead.
+                debug!("read 0 bytes off the worker control channel, it's dead");
+                Err(WorkerDied.into())
+            }
+            Err(e) => Err(e),
+        };
+        let action = match result {
+            Ok(action) => action,
+            Err(e) if acked => {
+                warn!("could not handle a message from the worker"; "worker_id" => &id, "error" => ?e);
+                continue;
+            }
+            Err(e) => {
+                let error = e.context("Receiving an ack from the control channel");
+                // The receiving end only goes away when we exit:
+                let _ = sender.send(Action::Failed(id, pid, error)).await;
+                return;
+            }
+        };
+        acked = acked || matches!(action, Action::Ack(..));
+        let _ = sender.send(action).await;
+    }
+}
+
+/// Translates a `line` that the worker `id` sent on its control
+/// channel into an action, checking that acks carry its `token`.
+fn control_action(line: &str, id: &str, token: &str, from: Option<u32>) -> Result<Action> {
+    let msg: WorkerControlMessage = serde_json::from_str(line)?;
+    if msg.id() != id {
+        bail!(
+            "Received a message for ID {:?}, but expected {:?}",
+            msg.id(),
+            id
+        );
+    }
+    let id = id.to_string();
+    match msg {
+        WorkerControlMessage::Ack {
+            token: acked_token,
+            details,
+            ..
+        } => {
+            if acked_token.as_deref() != Some(token) {
+                bail!(
+                    "Received ack for ID {:?} without the worker's ack token",
+                    id
+                );
+            }
+            Ok(Action::Ack(id, details, from))
         }
+        WorkerControlMessage::ExtendTimeout { by, .. } => Ok(Action::ExtendTimeout(id, by, from)),
+        WorkerControlMessage::Progress { message, .. } => Ok(Action::Status(id, message)),
+        WorkerControlMessage::Unready { reason, .. } => Ok(Action::Unready(id, reason, from)),
+        WorkerControlMessage::Ready { .. } => Ok(Action::Ready(id, from)),
+        WorkerControlMessage::Reply { request, reply, .. } => Ok(Action::Reply(id, request, reply)),
     }
 }
 
+/// Binds a notification socket for workers, and starts translating
+/// the notifications that arrive on it into actions. Returns the
+/// socket's address and the map from PIDs to worker IDs that the
+/// translation uses.
+#[cfg(target_os = "linux")]
+fn listen_for_notifications(sender: Sender<Action>) -> Result<(String, NotifyingWorkers)> {
+    let listener = sd_notify::Listener::bind()?;
+    let address = listener.address().to_string();
+    let workers: NotifyingWorkers = Default::default();
+    let by_pid = workers.clone();
+    Task::spawn(LogScoped::new(slog_scope::logger(), async move {
+        loop {
+            let (pid, notification) = match listener.receive().await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("could not receive a worker notification"; "error" => ?e);
+                    continue;
+                }
+            };
+            let id = match by_pid.lock().get(&pid) {
+                Some(id) => id.to_string(),
+                None => {
+                    debug!("ignoring notification from unknown process"; "pid" => pid);
+                    continue;
+                }
+            };
+            for (variable, value) in sd_notify::parse(&notification) {
+                let action = match (variable, value) {
+                    ("READY", "1") => {
+                        Action::Ack(id.to_string(), AckDetails::default(), Some(pid))
+                    }
+                    ("STATUS", status) => Action::Status(id.to_string(), status.to_string()),
+                    ("EXTEND_TIMEOUT_USEC", usec) => match usec.parse() {
+                        Ok(usec) => Action::ExtendTimeout(
+                            id.to_string(),
+                            Duration::from_micros(usec),
+                            Some(pid),
+                        ),
+                        Err(_) => {
+                            debug!("ignoring malformed timeout extension"; "worker_id" => &id, "value" => usec);
+                            continue;
+                        }
+                    },
+                    ("WATCHDOG", "1") => Action::Alive(id.to_string()),
+                    _ => {
+                        debug!("ignoring worker notification"; "worker_id" => &id, "variable" => variable, "value" => value);
+                        continue;
+                    }
+                };
+                // The receiving end only goes away when we exit:
+                let _ = sender.send(action).await;
+            }
+        }
+    }))
+    .detach();
+    Ok((address, workers))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen_for_notifications(_sender: Sender<Action>) -> Result<(String, NotifyingWorkers)> {
+    bail!("Worker notification sockets are only supported on Linux")
+}
+
+/// The shell that runs programs with [`configuration::Program.shell`] set.
+const SHELL: &str = "/bin/sh";
+
 /// The environment variable name used to pass the worker ID to a
 /// subprocess in the fork/exec method. It is `$KLEINHIRN_WORKER_ID`.
 pub const WORKER_ID_ENV: &str = "KLEINHIRN_WORKER_ID";
 
+/// The environment variable name used to pass the token that the
+/// worker has to include in its ack message. It is
+/// `$KLEINHIRN_ACK_TOKEN`.
+pub const ACK_TOKEN_ENV: &str = "KLEINHIRN_ACK_TOKEN";
+
+/// The environment variable name used to pass the worker's slot
+/// index (a number in `0..count`, or past it for a replacement that
+/// starts while the worker it replaces is still running). It is
+/// `$KLEINHIRN_WORKER_INDEX`.
+pub const WORKER_INDEX_ENV: &str = "KLEINHIRN_WORKER_INDEX";
+
 /// The environment variable name used to pass the worker's control
 /// channel FD number. It is `$KLEINHIRN_CONTROL_FD`.
 pub const WORKER_CONTROL_CHANNEL_ENV: &str = "KLEINHIRN_STATUS_FD";
 
+/// The environment variable name used to pass the FD number that the
+/// worker reads supervisor requests from. It is
+/// `$KLEINHIRN_REQUESTS_FD`; fork/exec workers read them off their
+/// control channel, preloaded workers off a socket of their own that
+/// the preloader forwards requests to.
+pub const REQUESTS_FD_ENV: &str = "KLEINHIRN_REQUESTS_FD";
+
 /// The environment variable name used to pass the service name. It is
 /// `$KLEINHIRN_NAME`.
 pub const NAME_ENV: &str = "KLEINHIRN_NAME";
 
 /// The environment variable name used to pass the version of the
 /// service. It is `$KLEINHIRN_VERSION`.
-#[allow(dead_code)]
 pub const VERSION_ENV: &str = "KLEINHIRN_VERSION";
@@ -5,11 +5,18 @@
 
 #![recursion_limit = "2048"] // select! needs a higher recursion limit /:
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use async_channel::{unbounded, Receiver, Sender};
+use configuration::FaultPolicy;
 use fork_exec::ForkExec;
 use futures::select;
-use futures::{future::FutureExt, Stream, StreamExt};
+use futures::{
+    future::{select_all, Future, FutureExt},
+    Stream, StreamExt,
+};
+use futures_ticker::Ticker;
 use health::{HealthIndicator, State};
+use nix::sys::signal::{kill, Signal};
 use nix::unistd::Pid;
 use parking_lot::Mutex;
 #[cfg(target_os = "linux")]
@@ -17,21 +24,44 @@ use preloader::Preloader;
 use preloader::PreloaderDied;
 use process_control::{Message, ProcessControl};
 use reaper::Zombies;
-use slog::o;
+use sd_notify::Notifier;
+use slog::{o, Logger};
 use slog_scope::{crit, debug, info, warn};
-use std::{convert::Infallible, sync::Arc, time::Instant};
+use smol::{Async, Task, Timer};
+use socket_activation::ListenFds;
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    io::Read,
+    os::unix::net::UnixStream,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use worker_ack::{AckDetails, WorkerOp};
 use worker_set::{
-    MiserableCondition, Tick, Todo, WorkerAcked, WorkerDeath, WorkerLaunchFailure, WorkerLaunched,
-    WorkerRequested, WorkerSet,
+    BootProgress, MiserableCondition, Recover, Tick, Todo, WorkerAcked, WorkerDeath,
+    WorkerExtendTimeout, WorkerKilled, WorkerLaunchFailure, WorkerLaunched, WorkerReadiness,
+    WorkerRequested, WorkerSet, WorkerStatus,
 };
 
 mod fork_exec;
 mod health;
-mod preloader;
-mod process_control;
+mod probe;
 
 pub mod configuration;
+pub mod preloader;
+pub mod process_control;
 pub mod reaper;
+pub mod sd_notify;
+pub mod socket_activation;
+pub mod template;
 pub mod worker_ack;
 pub mod worker_set;
 
@@ -43,7 +73,7 @@ impl Machine {
         Machine(Arc::new(Mutex::new(Some(set))))
     }
 
-    fn interrogate<T>(&self, with: fn(&WorkerSet) -> T) -> T {
+    fn interrogate<T>(&self, with: impl FnOnce(&WorkerSet) -> T) -> T {
         self.0.lock().as_ref().map(with).unwrap()
     }
 
@@ -57,13 +87,362 @@ impl Machine {
 impl HealthIndicator for Machine {
     fn health_check(&self) -> health::State {
         self.interrogate(|machine| match machine {
-            WorkerSet::Running(_) => State::Healthy,
-            WorkerSet::Startup(_) => State::Unhealthy(anyhow!("still starting up").into()),
+            WorkerSet::Running(_) => match machine.unready().as_slice() {
+                [] => State::Healthy,
+                unready => {
+                    let reasons: Vec<String> = unready
+                        .iter()
+                        .map(|(index, reason)| format!("{}: {}", index, reason))
+                        .collect();
+                    State::Unhealthy(
+                        anyhow!("workers are not ready: {}", reasons.join(", ")).into(),
+                    )
+                }
+            },
+            WorkerSet::Startup(_) => match machine.booting() {
+                Some((step, since)) => State::Unhealthy(
+                    anyhow!(
+                        "still starting up: loading {:?} for {:?}",
+                        step,
+                        since.elapsed()
+                    )
+                    .into(),
+                ),
+                // The workers' progress tells a slow boot from a stuck one:
+                None => State::Unhealthy(anyhow!("still starting up: {:?}", machine).into()),
+            },
             state => State::Unhealthy(anyhow!("Machine in unhealthy state: {:?}", state).into()),
         })
     }
 }
 
+/// The machines of all worker groups, by name. They are healthy only
+/// if every group is healthy.
+#[derive(Clone)]
+struct Groups(Vec<(String, Machine)>);
+
+impl HealthIndicator for Groups {
+    fn health_check(&self) -> health::State {
+        let problems: Vec<String> = self
+            .0
+            .iter()
+            .filter_map(|(name, machine)| match machine.health_check() {
+                State::Healthy => None,
+                State::Unhealthy(e) => Some(format!("{}: {}", name, e)),
+            })
+            .collect();
+        if problems.is_empty() {
+            State::Healthy
+        } else {
+            State::Unhealthy(anyhow!("{}", problems.join("; ")).into())
+        }
+    }
+}
+
+impl Groups {
+    /// Returns the state of each group, for humans to read.
+    fn status(&self) -> String {
+        let states: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, machine)| {
+                format!("{}: {}", name, machine.interrogate(|m| format!("{:?}", m)))
+            })
+            .collect();
+        states.join("; ")
+    }
+
+    fn all_running(&self) -> bool {
+        self.0
+            .iter()
+            .all(|(_, machine)| machine.interrogate(|m| matches!(m, WorkerSet::Running(_))))
+    }
+}
+
+/// Keeps the service manager (if the supervisor runs under one) up to
+/// date on the worker groups.
+struct ServiceManager {
+    notifier: Option<Notifier>,
+    version: Option<String>,
+    groups: Groups,
+    ready: AtomicBool,
+    status: Mutex<String>,
+}
+
+impl ServiceManager {
+    fn new(notifier: Option<Notifier>, version: Option<String>, groups: Groups) -> Self {
+        ServiceManager {
+            notifier,
+            version,
+            groups,
+            ready: AtomicBool::new(false),
+            status: Mutex::new(String::new()),
+        }
+    }
+
+    fn notify(&self, what: fn(&Notifier) -> Result<()>) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(e) = what(notifier) {
+                warn!("could not notify the service manager"; "error" => ?e);
+            }
+        }
+    }
+
+    /// Sends the current status of all groups if it changed, and
+    /// signals readiness once all groups are running for the first
+    /// time.
+    fn update(&self) {
+        let notifier = match &self.notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        let status = match &self.version {
+            Some(version) => format!("version {}; {}", version, self.groups.status()),
+            None => self.groups.status(),
+        };
+        {
+            let mut last = self.status.lock();
+            if *last != status {
+                if let Err(e) = notifier.status(&status) {
+                    warn!("could not update the service status"; "error" => ?e);
+                }
+                *last = status;
+            }
+        }
+        if !self.ready.load(Ordering::SeqCst) && self.groups.all_running() {
+            info!("all worker groups are running, notifying readiness");
+            self.ready.store(true, Ordering::SeqCst);
+            self.notify(Notifier::ready);
+        }
+    }
+
+    /// Returns a stream that ticks as often as the service manager's
+    /// watchdog needs to be fed.
+    fn watchdog_ticker(&self) -> Box<dyn Stream<Item = Instant> + std::marker::Unpin> {
+        match self.notifier.as_ref().and_then(Notifier::watchdog_interval) {
+            Some(interval) => Box::new(Ticker::new(interval / 2)),
+            None => Box::new(futures::stream::pending()),
+        }
+    }
+}
+
+/// Polls a future with a scoped logger, so that everything logged
+/// while it runs carries that logger's keys.
+pub(crate) struct LogScoped<F> {
+    logger: Logger,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> LogScoped<F> {
+    pub(crate) fn new(logger: Logger, inner: F) -> Self {
+        LogScoped {
+            logger,
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl<F: Future> Future for LogScoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let inner = &mut this.inner;
+        slog_scope::scope(&this.logger, || inner.as_mut().poll(cx))
+    }
+}
+
+/// Returned from [`run`] when the worker set is faulted and the
+/// supervisor is configured to exit in that case.
+#[derive(Error, Debug, PartialEq)]
+#[error("the worker set is faulted, exiting with code {code}")]
+pub struct FaultExit {
+    pub code: i32,
+}
+
+/// Returned from [`run`] when the supervisor receives a signal that
+/// asks it to terminate.
+#[derive(Error, Debug, PartialEq)]
+#[error("received signal {signal}, terminating")]
+pub struct Terminated {
+    pub signal: i32,
+}
+
+/// Sets up handlers for the signals that terminate the supervisor,
+/// and returns a future that resolves to the first one received.
+fn termination_signal() -> Result<impl Future<Output = Result<i32>>> {
+    let mut signals = vec![];
+    for &signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        let (read, write) =
+            UnixStream::pair().context("Could not initialize signal handler socket pair")?;
+        signal_hook::pipe::register(signal, write)
+            .with_context(|| format!("registering handler for signal {}", signal))?;
+        let mut read = Async::new(read)?;
+        signals.push(
+            async move {
+                let mut buf = [0u8; 1];
+                read.read_with_mut(|io| io.read(&mut buf))
+                    .await
+                    .context("Failed to read from signal notification pipe")?;
+                Ok(signal)
+            }
+            .boxed(),
+        );
+    }
+    Ok(select_all(signals).map(|(res, _, _)| res))
+}
+
+/// Reaps all children and tells every worker group about their
+/// deaths.
+async fn dispatch_deaths(mut zombies: Zombies, groups: Vec<Sender<Pid>>) -> Infallible {
+    loop {
+        match zombies.reap().await {
+            Ok(pid) => {
+                info!("reaped child"; "pid" => pid.as_raw());
+                for group in groups.iter() {
+                    // The group's supervisor only goes away when we exit:
+                    let _ = group.try_send(pid);
+                }
+            }
+            Err(e) => info!("failed to reap"; "error" => ?e),
+        }
+    }
+}
+
+/// Sets up handlers for the configured worker signals, and returns a
+/// future that sends each signal's request to every worker group
+/// when the signal arrives.
+fn worker_signal_requests(
+    worker_signals: Vec<(Signal, WorkerOp)>,
+    groups: Vec<Sender<WorkerOp>>,
+) -> Result<impl Future<Output = Infallible>> {
+    let mut handlers = vec![];
+    for (signal, op) in worker_signals {
+        let (read, write) =
+            UnixStream::pair().context("Could not initialize signal handler socket pair")?;
+        signal_hook::pipe::register(signal as i32, write)
+            .with_context(|| format!("registering handler for signal {}", signal))?;
+        let mut read = Async::new(read)?;
+        let groups = groups.clone();
+        handlers.push(async move {
+            let mut buf = [0u8; 1];
+            loop {
+                if let Err(e) = read.read_with_mut(|io| io.read(&mut buf)).await {
+                    warn!("failed to read from signal notification pipe";
+                          "signal" => %signal, "error" => ?e);
+                    return;
+                }
+                info!("received signal, sending request to workers";
+                      "signal" => %signal, "request" => ?op);
+                for group in groups.iter() {
+                    // The group's supervisor only goes away when we exit:
+                    let _ = group.try_send(op.clone());
+                }
+            }
+        });
+    }
+    Ok(async move {
+        futures::future::join_all(handlers).await;
+        futures::future::pending().await
+    })
+}
+
+/// How long sending a request to a worker may take before the
+/// supervisor gives up on it.
+const REQUEST_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends `op` to the worker `id` in the background, giving up after
+/// [`REQUEST_SEND_TIMEOUT`]. Returns an error if the worker can't
+/// receive requests at all.
+fn send_to_worker(proc: &mut dyn ProcessControl, id: &str, op: WorkerOp) -> Result<()> {
+    let sending = proc.send_to_worker(id, op.clone())?;
+    let id = id.to_string();
+    Task::spawn(LogScoped::new(slog_scope::logger(), async move {
+        select! {
+            result = sending.fuse() => match result {
+                Ok(()) => debug!("sent request to worker"; "worker_id" => &id, "request" => ?op),
+                Err(e) => warn!("could not send request to worker";
+                                "worker_id" => &id, "request" => ?op, "error" => ?e),
+            },
+            _ = Timer::after(REQUEST_SEND_TIMEOUT).fuse() => {
+                warn!("timed out sending request to worker";
+                      "worker_id" => &id, "request" => ?op);
+            }
+        }
+    }))
+    .detach();
+    Ok(())
+}
+
+/// Sends `op` to every worker in the group that has acked.
+fn send_to_workers(machine: &Machine, proc: &mut dyn ProcessControl, op: WorkerOp) {
+    for id in machine.interrogate(|m| m.acked_workers()) {
+        if let Err(e) = send_to_worker(proc, &id, op.clone()) {
+            debug!("could not send request to worker";
+                   "worker_id" => &id, "request" => ?op, "error" => ?e);
+        }
+    }
+}
+
+fn terminate_worker(pid: Pid) {
+    if let Err(e) = kill(pid, Signal::SIGTERM) {
+        warn!("failed to kill worker"; "pid" => pid.as_raw(), "error" => ?e);
+    }
+}
+
+/// Asks the worker `pid` to drain, and sends it SIGTERM if it is
+/// still around after `timeout`. Workers that can't be asked get
+/// SIGTERM right away.
+fn drain_worker(machine: &Machine, proc: &mut dyn ProcessControl, pid: Pid, timeout: Duration) {
+    let id = match machine.interrogate(|m| m.worker_id(pid)) {
+        Some(id) => id,
+        None => return terminate_worker(pid),
+    };
+    match send_to_worker(proc, &id, WorkerOp::Drain) {
+        Ok(()) => {
+            info!("asked worker to drain";
+                  "worker_id" => &id, "pid" => pid.as_raw(), "drain_timeout" => ?timeout);
+            let machine = machine.clone();
+            Task::spawn(LogScoped::new(slog_scope::logger(), async move {
+                Timer::after(timeout).await;
+                // Once the worker is reaped, its PID can belong to
+                // any other process:
+                if let Some(pid) = machine.interrogate(|m| m.worker_pid(&id)) {
+                    info!("worker did not drain in time, killing it";
+                          "worker_id" => &id, "pid" => pid.as_raw());
+                    terminate_worker(pid);
+                }
+            }))
+            .detach();
+        }
+        Err(e) => {
+            debug!("could not ask worker to drain, killing it";
+                   "worker_id" => &id, "pid" => pid.as_raw(), "error" => ?e);
+            terminate_worker(pid);
+        }
+    }
+}
+
+/// Tells the worker set about a reaped child, and returns the ID of
+/// the worker that the child was, if any. If the child is the
+/// `preloader` that the workers get forked off, the worker set can't
+/// work anymore.
+fn handle_reaped(machine: &Machine, pid: Option<Pid>, preloader: Option<Pid>) -> Option<String> {
+    match pid {
+        Some(pid) if Some(pid) == preloader => {
+            info!("preloader process is dead"; "pid" => pid.as_raw());
+            machine.update(|m| m.on_miserable_condition(MiserableCondition::PreloaderDied));
+            None
+        }
+        Some(pid) => {
+            let id = machine.interrogate(|m| m.worker_id(pid));
+            machine.update(|m| m.on_worker_death(WorkerDeath::new(pid)));
+            id
+        }
+        None => None,
+    }
+}
+
 // let's try (at least on this function call level) to ensure all
 // problematic conditions are handled in a way that doesn't leave this
 // loop:
@@ -75,24 +454,59 @@ impl HealthIndicator for Machine {
 )]
 async fn supervise(
     machine: Machine,
-    mut zombies: Zombies,
+    deaths: Receiver<Pid>,
+    requests: Receiver<WorkerOp>,
     mut proc: Box<dyn ProcessControl>,
     ticker: Box<dyn Stream<Item = Instant> + std::marker::Unpin>,
-) -> Infallible {
-    let mut known_broken = false;
+    on_fault: FaultPolicy,
+    service: Arc<ServiceManager>,
+) -> Result<Infallible> {
+    let mut broken_since: Option<Instant> = None;
     let mut ticker = ticker.fuse();
+    let mut watchdog = service.watchdog_ticker().fuse();
+    let preloader = proc.preloader_pid();
 
     loop {
+        service.update();
         if machine.interrogate(|m| m.working()).is_none() {
-            // We're broken. Just reap children & wait quietly for the
-            // sweet release of death.
-            if !known_broken {
-                warn!("The workers are in a faulty state! Marking self as unhealthy & reaping any workers that exit.");
-                known_broken = true;
-            }
-            match zombies.reap().await {
-                Ok(pid) => info!("reaped child"; "pid" => ?pid),
-                Err(e) => info!("failed to reap"; "error" => ?e),
+            let since = *broken_since.get_or_insert_with(|| {
+                warn!("The workers are in a faulty state! Marking self as unhealthy & reaping any workers that exit.";
+                      "on_fault" => ?on_fault);
+                Instant::now()
+            });
+            match on_fault {
+                FaultPolicy::Stay => {
+                    // We're broken. Just reap children & wait quietly for the
+                    // sweet release of death - but keep the watchdog fed, so
+                    // that it isn't the service manager that kills us:
+                    select! {
+                        _ = watchdog.next() => service.notify(Notifier::watchdog),
+                        pid = deaths.recv().fuse() => {
+                            if let Some(id) = handle_reaped(&machine, pid.ok(), preloader) {
+                                proc.forget_worker(&id);
+                            }
+                        }
+                    }
+                }
+                FaultPolicy::Exit { code } => {
+                    crit!("Exiting, since the worker set is faulted"; "code" => code);
+                    return Err(FaultExit { code }.into());
+                }
+                FaultPolicy::Recover { cooldown } => {
+                    select! {
+                        _ = Timer::at(since + cooldown).fuse() => {
+                            info!("Attempting to recover from the faulty state");
+                            machine.update(|m| m.on_recover(Recover));
+                            broken_since = None;
+                        }
+                        _ = watchdog.next() => service.notify(Notifier::watchdog),
+                        pid = deaths.recv().fuse() => {
+                            if let Some(id) = handle_reaped(&machine, pid.ok(), preloader) {
+                                proc.forget_worker(&id);
+                            }
+                        }
+                    }
+                }
             }
             continue;
         }
@@ -104,44 +518,55 @@ async fn supervise(
         {
             None => {}
             Some(Todo::KillProcess(pid)) => {
-                // TODO
-                info!("Should kill"; "pid" => pid.as_raw());
+                info!("killing worker"; "pid" => pid.as_raw());
+                if let Some(id) = machine.interrogate(|m| m.worker_id(pid)) {
+                    proc.forget_worker(&id);
+                }
+                match machine.interrogate(|m| m.drain_timeout()) {
+                    Some(timeout) => drain_worker(&machine, proc.as_mut(), pid, timeout),
+                    None => terminate_worker(pid),
+                }
+                machine.update(move |m| m.on_worker_killed(WorkerKilled::new(pid)));
             }
-            Some(Todo::LaunchProcess) => {
-                info!("Need to launch a process");
-                match proc.spawn_process().await {
+            Some(Todo::LaunchProcess(index)) => {
+                info!("Need to launch a process"; "worker_index" => index);
+                match proc.spawn_process(index).await {
                     Ok(id) => {
-                        info!("requested launch"; "id" => &id);
+                        info!("requested launch"; "id" => &id, "worker_index" => index);
                         machine.update(move |m| {
-                            m.on_worker_requested(WorkerRequested::new(id.clone()))
+                            m.on_worker_requested(WorkerRequested::new(id.clone(), index))
                         });
                     }
                     Err(e) => {
+                        warn!("failed to launch"; "error" => ?e);
+                        let reason = format!("{:#}", e);
                         machine.update(move |m| {
-                            m.on_worker_launch_failure(WorkerLaunchFailure::new(None))
+                            m.on_worker_launch_failure(WorkerLaunchFailure::new(
+                                None,
+                                reason.clone(),
+                            ))
                         });
-                        warn!("failed to launch"; "error" => ?e);
                     }
                 }
             }
         }
 
-        // Read events off the environment:
+        // Read events off the environment, noting which worker the
+        // process control can forget about (it is busy reading):
+        let mut gone = None;
         select! {
+            _ = watchdog.next() => service.notify(Notifier::watchdog),
             tick = ticker.next() => {
                 if let Some(tick) = tick {
                     machine.update(|m| m.on_tick(Tick::new(tick)));
                 }
             }
-            // TODO: check the preloader PID also - could be that the
-            // control pipe is held open by a broken child.
-            res = zombies.reap().fuse() => {
-                match res {
-                    Ok(pid) => {
-                        info!("reaped child"; "pid" => pid.as_raw());
-                        machine.update(|m| m.on_worker_death(WorkerDeath::new(pid)));
-                    }
-                    Err(e) => info!("failed to reap"; "error" => ?e)
+            // Workers hold the preloader's control channel open, so
+            // the preloader's death shows up here rather than as EOF:
+            pid = deaths.recv().fuse() => gone = handle_reaped(&machine, pid.ok(), preloader),
+            op = requests.recv().fuse() => {
+                if let Ok(op) = op {
+                    send_to_workers(&machine, proc.as_mut(), op);
                 }
             }
             msg = proc.next_message().fuse() => {
@@ -154,52 +579,197 @@ async fn supervise(
                     }
                     Err(e) => info!("could not read preloader message"; "error" => ?e),
                     Ok(Launched{id, pid}) => {
-                        machine.update(move |m| m.on_worker_launched(WorkerLaunched::new(id.clone(), Pid::from_raw(pid as i32))))
+                        let launched = WorkerLaunched::new(id.clone(), Pid::from_raw(pid as i32));
+                        machine.update(move |m| m.on_worker_launched(launched.clone()));
+                        // Workers that were given up on get killed
+                        // rather than tracked:
+                        if machine.interrogate(|m| m.worker_pid(&id)).is_none() {
+                            gone = Some(id);
+                        }
                     }
-                    Ok(Ack{id}) => {
-                        machine.update(move |m| m.on_worker_acked(WorkerAcked::new(id.clone())))
+                    Ok(Ack{id, details, sender}) => {
+                        info!("worker acked";
+                              "worker_id" => &id,
+                              "sender_pid" => ?sender,
+                              "acked_version" => ?details.version,
+                              "build_info" => ?details.build_info.as_ref().map(|info| info.to_string()),
+                        );
+                        match version_mismatch(service.version.as_deref(), &details) {
+                            Some(reason) => machine.update(move |m| {
+                                m.on_worker_launch_failure(WorkerLaunchFailure::new(Some(id.clone()), reason.clone()))
+                            }),
+                            None => machine.update(move |m| {
+                                let ack = WorkerAcked::new(id.clone());
+                                m.on_worker_acked(match sender {
+                                    Some(pid) => ack.with_sender(Pid::from_raw(pid as i32)),
+                                    None => ack,
+                                })
+                            }),
+                        }
+                    }
+                    Ok(ExtendTimeout{id, by, sender}) => {
+                        machine.update(move |m| {
+                            let ext = WorkerExtendTimeout::new(id.clone(), by);
+                            m.on_worker_extend_timeout(match sender {
+                                Some(pid) => ext.with_sender(Pid::from_raw(pid as i32)),
+                                None => ext,
+                            })
+                        })
+                    }
+                    Ok(Unready{id, reason, sender}) => {
+                        machine.update(move |m| {
+                            let report = WorkerReadiness::unready(id.clone(), reason.clone());
+                            m.on_worker_readiness(match sender {
+                                Some(pid) => report.with_sender(Pid::from_raw(pid as i32)),
+                                None => report,
+                            })
+                        })
+                    }
+                    Ok(Ready{id, sender}) => {
+                        machine.update(move |m| {
+                            let report = WorkerReadiness::ready(id.clone());
+                            m.on_worker_readiness(match sender {
+                                Some(pid) => report.with_sender(Pid::from_raw(pid as i32)),
+                                None => report,
+                            })
+                        })
+                    }
+                    Ok(Status{id, status}) => {
+                        machine.update(move |m| m.on_worker_status(WorkerStatus::new(id.clone(), status.clone())))
+                    }
+                    Ok(Reply{id, request, reply}) => {
+                        info!("worker replied";
+                              "worker_id" => &id,
+                              "request" => request,
+                              "reply" => %reply,
+                        );
                     }
+                    Ok(Alive{id}) => debug!("worker is alive"; "worker_id" => id),
                     Ok(LaunchError{id, pid, error}) => {
                         warn!("error launching worker";
                               "worker_id" => ?id,
                               "pid" => ?pid,
                               "error" => ?error,
                         );
+                        let reason = format!("{:#}", error);
                         machine.update(move |m| {
-                            m.on_worker_launch_failure(WorkerLaunchFailure::new(Some(id.clone())))
+                            m.on_worker_launch_failure(WorkerLaunchFailure::new(Some(id.clone()), reason.clone()))
                         });
                     }
                 }
             }
         };
+        if let Some(id) = gone {
+            proc.forget_worker(&id);
+        }
     }
 }
 
-/// Starts the process supervisor with the configured worker set.
-///
-/// This function never exits in the "normal" case.
-pub async fn run(settings: configuration::Config) -> Result<Infallible> {
-    let _g = slog_scope::set_global_logger(
-        slog_scope::logger().new(o!("service" => settings.supervisor.name.to_string())),
-    );
-
-    let terminations =
-        reaper::setup_child_exit_handler().context("Could not set up child exit handler")?;
+/// Returns why a worker's ack doesn't count if the worker reports a
+/// different version than the `expected` one. Workers that don't
+/// report a version are trusted.
+fn version_mismatch(expected: Option<&str>, details: &AckDetails) -> Option<String> {
+    match (expected, &details.version) {
+        (Some(expected), Some(actual)) if expected != actual => Some(format!(
+            "worker loaded version {:?}, but {:?} is configured",
+            actual, expected
+        )),
+        _ => None,
+    }
+}
 
-    let ticker = settings.worker.ack_ticker();
-    let mut proc: Box<dyn ProcessControl> = match &settings.worker.kind {
+fn process_control(
+    settings: &configuration::Config,
+    worker: &configuration::WorkerConfig,
+    listen_fds: &Option<ListenFds>,
+    earlier: &[(String, Box<dyn ProcessControl>)],
+) -> Result<Box<dyn ProcessControl>> {
+    Ok(match &worker.kind {
+        #[cfg(target_os = "linux")]
+        configuration::WorkerKind::SharedPreloader(shared) => {
+            info!("sharing preloader";
+                  "preloader_group" => &shared.group,
+                  "start_expression" => &shared.start_expression,
+            );
+            let (_, preloader) = earlier
+                .iter()
+                .find(|(name, _)| name == &shared.group)
+                .with_context(|| {
+                    format!(
+                        "No worker group {:?} is defined before the group sharing its preloader",
+                        &shared.group
+                    )
+                })?;
+            preloader
+                .share(&shared.start_expression)
+                .with_context(|| format!("Can not share worker group {:?}", &shared.group))?
+        }
         #[cfg(target_os = "linux")]
         configuration::WorkerKind::Ruby(rb) => {
-            let gemfile = settings.canonical_path(&rb.gemfile);
-            let load = settings.canonical_path(&rb.load);
+            let rb = configuration::Ruby {
+                gemfile: rb
+                    .gemfile
+                    .as_ref()
+                    .map(|path| settings.canonical_path(path)),
+                load: rb
+                    .load
+                    .iter()
+                    .map(|path| settings.canonical_path(path))
+                    .collect(),
+                cwd: rb.cwd.as_ref().map(|path| settings.canonical_path(path)),
+                ..rb.clone()
+            };
             info!("loading ruby";
-                  "gemfile" => ?gemfile,
-                  "load" => ?load,
+                  "gemfile" => ?rb.gemfile,
+                  "load" => ?rb.load,
                   "start_expression" => &rb.start_expression,
+                  "ruby_flags" => ?rb.ruby_flags,
+                  "cwd" => ?rb.cwd,
             );
             Box::new(
-                Preloader::for_ruby(&gemfile, &load, &rb.start_expression)
-                    .context("Failed to spawn the preloader")?,
+                Preloader::for_ruby(
+                    &settings.supervisor.name,
+                    settings.supervisor.version.as_deref(),
+                    &rb,
+                )
+                .context("Failed to spawn the preloader")?
+                .with_timeouts(rb.boot_timeout, rb.load_timeout),
+            )
+        }
+        #[cfg(target_os = "linux")]
+        configuration::WorkerKind::Python(py) => {
+            info!("loading python";
+                  "python" => ?py.python,
+                  "module" => &py.module,
+                  "start_callable" => &py.start_callable,
+            );
+            Box::new(
+                Preloader::for_python(
+                    &settings.supervisor.name,
+                    settings.supervisor.version.as_deref(),
+                    &py.python,
+                    &py.module,
+                    &py.start_callable,
+                    &settings.canonical_path("."),
+                )
+                .context("Failed to spawn the preloader")?,
+            )
+        }
+        #[cfg(target_os = "linux")]
+        configuration::WorkerKind::Preloader(p) => {
+            let cwd = p.cwd.as_ref().map(|cwd| settings.canonical_path(cwd));
+            info!("starting preloader";
+                  "cmdline" => ?p.cmdline,
+                  "cwd" => ?cwd,
+            );
+            Box::new(
+                Preloader::for_program(
+                    &settings.supervisor.name,
+                    settings.supervisor.version.as_deref(),
+                    p,
+                    cwd.as_deref(),
+                )
+                .context("Failed to spawn the preloader")?,
             )
         }
         configuration::WorkerKind::Program(p) => {
@@ -207,21 +777,170 @@ pub async fn run(settings: configuration::Config) -> Result<Infallible> {
                   "cwd" => ?p.cwd,
                   "cmdline" => ?p.cmdline,
             );
-            Box::new(ForkExec::for_program(p).context("Failed to spawn the program")?)
+            Box::new(
+                ForkExec::for_program(
+                    &settings.supervisor.name,
+                    settings.supervisor.version.as_deref(),
+                    p,
+                    listen_fds.clone(),
+                    worker.ack_timeout,
+                )
+                .context("Failed to spawn the program")?,
+            )
         }
+    })
+}
+
+/// How often the service status gets refreshed while preloaders boot.
+const BOOT_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts and initializes the process controls of all worker groups
+/// in order, so later groups can share an earlier group's preloader.
+/// The files that preloaders load show up in their groups' machines.
+async fn start_process_controls(
+    settings: &configuration::Config,
+    groups: &[configuration::WorkerGroup],
+    machines: &[(String, Machine)],
+    listen_fds: &Option<ListenFds>,
+) -> Result<(Vec<(String, Box<dyn ProcessControl>)>, Vec<Logger>)> {
+    let mut procs: Vec<(String, Box<dyn ProcessControl>)> = vec![];
+    let mut loggers = vec![];
+    for (group, (_, machine)) in groups.iter().zip(machines) {
+        let logger = slog_scope::logger().new(o!("group" => group.name.to_string()));
+        let mut proc = slog_scope::scope(&logger, || {
+            process_control(settings, &group.worker, listen_fds, &procs)
+        })?;
+        let on_loading = |file: &Path| {
+            let step = file.display().to_string();
+            let since = Instant::now();
+            machine.update(|m| m.on_boot_progress(BootProgress::loading(step.clone(), since)));
+        };
+        LogScoped::new(logger.clone(), proc.as_mut().initialize(&on_loading)).await?;
+        machine.update(|m| m.on_boot_progress(BootProgress::done()));
+        procs.push((group.name.to_string(), proc));
+        loggers.push(logger);
+    }
+    Ok((procs, loggers))
+}
+
+/// Starts the process supervisor with the configured worker groups.
+///
+/// This function never exits in the "normal" case. If a worker set
+/// is faulted and the supervisor is configured to exit on faults, it
+/// returns a [`FaultExit`] error.
+pub async fn run(mut settings: configuration::Config) -> Result<Infallible> {
+    // Resolve the version once, so all workers agree on it:
+    settings.supervisor.version = settings
+        .supervisor
+        .version
+        .as_deref()
+        .map(|version| template::expand_version(version, &settings.base_dir))
+        .transpose()
+        .context("Could not determine the service version")?;
+    let logger = slog_scope::logger().new(o!("service" => settings.supervisor.name.to_string()));
+    let logger = match &settings.supervisor.version {
+        Some(version) => logger.new(o!("version" => version.to_string())),
+        None => logger,
     };
+    let _g = slog_scope::set_global_logger(logger);
 
-    let machine = Machine::new(WorkerSet::new(settings.worker));
+    let groups = settings.all_worker_groups();
+    if groups.is_empty() {
+        bail!("No workers configured; add a [worker] or [[worker_group]] section");
+    }
+    let mut names = HashSet::new();
+    for group in groups.iter() {
+        if !names.insert(&group.name) {
+            bail!("Worker group name {:?} is used more than once", &group.name);
+        }
+    }
+
+    let terminations =
+        reaper::setup_child_exit_handler().context("Could not set up child exit handler")?;
+    let listen_fds = ListenFds::inherited().context("Could not pass on listen sockets")?;
+
+    let tickers: Vec<_> = groups.iter().map(|group| group.worker.ticker()).collect();
+    let machines: Vec<(String, Machine)> = groups
+        .iter()
+        .map(|group| {
+            let machine = Machine::new(WorkerSet::new(group.worker.clone()));
+            (group.name.to_string(), machine)
+        })
+        .collect();
+    let notifier = Notifier::from_env().context("Could not connect to the service manager")?;
+    let service = Arc::new(ServiceManager::new(
+        notifier,
+        settings.supervisor.version.clone(),
+        Groups(machines.clone()),
+    ));
+    let health_server =
+        health::healthcheck_server(settings.health_check.clone(), Groups(machines.clone())).fuse();
+    futures::pin_mut!(health_server);
+
+    // Serve health checks and keep the status fresh while the
+    // preloaders boot:
+    let boot = start_process_controls(&settings, &groups, &machines, &listen_fds).fuse();
+    futures::pin_mut!(boot);
+    let mut refresh = Ticker::new(BOOT_STATUS_INTERVAL).fuse();
+    let (procs, loggers) = loop {
+        select! {
+            res = boot => break res?,
+            _ = refresh.next() => service.update(),
+            res = health_server => {
+                crit!("healthcheck server terminated"; "result" => ?res);
+                unreachable!("the server should never terminate");
+            }
+        }
+    };
+    let terminated = termination_signal()?;
+    let worker_signals = settings.supervisor.worker_signals()?;
+
+    let mut supervisors = vec![];
+    let mut death_senders = vec![];
+    let mut request_senders = vec![];
+    for ((((_, machine), (_, proc)), logger), ticker) in
+        machines.iter().zip(procs).zip(loggers).zip(tickers)
+    {
+        let (sender, deaths) = unbounded();
+        death_senders.push(sender);
+        let (sender, requests) = unbounded();
+        request_senders.push(sender);
+        supervisors.push(LogScoped::new(
+            logger,
+            supervise(
+                machine.clone(),
+                deaths,
+                requests,
+                proc,
+                ticker,
+                settings.supervisor.on_fault,
+                service.clone(),
+            ),
+        ));
+    }
+    let worker_requests = worker_signal_requests(worker_signals, request_senders)?;
 
-    proc.as_mut().initialize().await?;
-    let health_server = health::healthcheck_server(settings.health_check, machine.clone());
-    select! {
-        _ = supervise(machine, terminations, proc, ticker).fuse() => {
-            unreachable!("supervise never quits.");
+    let result = select! {
+        (res, _, _) = select_all(supervisors).fuse() => {
+            // supervise only quits if it is configured to exit on faults:
+            res
         }
-        res = health_server.fuse() => {
+        signal = terminated.fuse() => {
+            let signal = signal?;
+            info!("received termination signal"; "signal" => signal);
+            Err(Terminated { signal }.into())
+        }
+        _ = dispatch_deaths(terminations, death_senders).fuse() => {
+            unreachable!("dispatching deaths never quits.");
+        }
+        _ = worker_requests.fuse() => {
+            unreachable!("dispatching worker requests never quits.");
+        }
+        res = health_server => {
             crit!("healthcheck server terminated"; "result" => ?res);
             unreachable!("the server should never terminate");
         }
-    }
+    };
+    service.notify(Notifier::stopping);
+    result
 }
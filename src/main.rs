@@ -1,9 +1,11 @@
 use anyhow::{Context, Result};
 use kleinhirn::*;
+use nix::sys::signal::{raise, signal, SigHandler, Signal};
 use slog::{o, Drain, Logger};
 use slog_json::Json;
 use slog_logfmt::Logfmt;
 use slog_scope::info;
+use std::convert::TryFrom;
 use std::io;
 use std::{env::current_dir, path::PathBuf};
 use structopt::StructOpt;
@@ -60,14 +62,29 @@ fn main() -> Result<()> {
             &config_file
         ))?;
     let log = setup_logger(&settings);
-    let _guard = slog_scope::set_global_logger(log);
+    let guard = slog_scope::set_global_logger(log);
 
     let cwd = current_dir()?;
     settings.base_dir = config_file.parent().map(|p| p.to_owned()).unwrap_or(cwd);
     info!("startup");
-    smol::run(async {
+    let result: Result<()> = smol::run(async {
         kleinhirn::run(settings).await?;
 
         Ok(())
-    })
+    });
+    if let Err(e) = &result {
+        if let Some(FaultExit { code }) = e.downcast_ref::<FaultExit>() {
+            // Flush the logs before exiting:
+            drop(guard);
+            std::process::exit(*code);
+        }
+        if let Some(Terminated { signal: signum }) = e.downcast_ref::<Terminated>() {
+            drop(guard);
+            // Terminate the way we would have without a handler:
+            let sig = Signal::try_from(*signum)?;
+            unsafe { signal(sig, SigHandler::SigDfl) }?;
+            raise(sig)?;
+        }
+    }
+    result
 }
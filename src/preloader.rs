@@ -1,24 +1,69 @@
 use self::machine::PreloaderState;
 use crate::{
-    process_control::{Message, ProcessControl},
-    worker_ack::{ControlChannel, WorkerControlMessage},
+    process_control::{Message, OnLoading, ProcessControl},
+    worker_ack::{ControlChannel, ControlLines, WorkerControlMessage, WorkerOp, WorkerRequest},
+    LogScoped,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
+use async_channel::{unbounded, Receiver, Sender};
 use async_trait::async_trait;
-use futures::io::{AsyncBufReadExt, AsyncWriteExt};
+use futures::{future::BoxFuture, select, FutureExt};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use slog_scope::debug;
-use slog_scope::info;
+use slog_scope::{error, info, warn};
+use smol::{Task, Timer};
 use std::collections::HashMap;
-use std::{path::PathBuf, time::Duration};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 mod logging;
-mod machine;
+pub mod machine;
 
 #[cfg(target_os = "linux")]
 mod linux;
 
+/// The version of the preloader protocol that this supervisor
+/// speaks. Preloaders announce theirs in their
+/// [`Hello`](PreloaderSpecificMessage::Hello) message, and ones that
+/// speak a different version are refused.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features that this supervisor supports:
+///
+/// * `spawn_expression`: `spawn` requests can carry an `expression`
+///   that overrides the preloader's start expression.
+/// * `ack_token`: `spawn` requests carry a `token` that the preloader
+///   passes to the worker in `$KLEINHIRN_ACK_TOKEN`, and that the
+///   worker's ack has to include.
+/// * `requests`: The preloader gives each worker a socket of its own
+///   (in `$KLEINHIRN_REQUESTS_FD`), and `forward` requests carry a
+///   [`WorkerRequest`] that the preloader writes to the socket of the
+///   worker with the given `id`.
+pub const CAPABILITIES: &[&str] = &["spawn_expression", "ack_token", "requests"];
+
+/// The environment variable that passes [`CAPABILITIES`] to the
+/// preloader, as a comma-separated list. It is
+/// `$KLEINHIRN_CAPABILITIES`.
+pub(crate) const CAPABILITIES_ENV: &str = "KLEINHIRN_CAPABILITIES";
+
+/// Returns the capabilities that both the supervisor and a preloader
+/// announcing `theirs` support.
+fn negotiate(theirs: &[String]) -> Vec<String> {
+    theirs
+        .iter()
+        .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+        .cloned()
+        .collect()
+}
+
 #[derive(PartialEq, Debug, Clone, Deserialize)]
 #[serde(untagged)]
 #[serde(rename_all = "snake_case")]
@@ -34,6 +79,14 @@ pub enum PreloaderMessage {
 #[serde(tag = "action")]
 #[serde(rename_all = "snake_case")]
 pub enum PreloaderSpecificMessage {
+    /// The first message the preloader sends: The protocol version it
+    /// speaks and the optional features it supports.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+
     /// The preloader is loading a file
     Loading {
         /// Path of the file being loaded
@@ -75,12 +128,93 @@ pub enum LogLevel {
 #[serde(tag = "op")]
 #[serde(rename_all = "snake_case")]
 enum PreloaderRequest {
-    Spawn { id: String },
+    Spawn {
+        id: String,
+        index: usize,
+        /// The expression that starts the worker, if it differs from
+        /// the one the preloader was started with.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expression: Option<String>,
+        /// The token that the worker has to present when it acks.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<AckToken>,
+    },
+    Forward {
+        id: String,
+        request: WorkerRequest,
+    },
+}
+
+/// An ack token, which stays out of the logs.
+#[derive(PartialEq, Serialize)]
+#[serde(transparent)]
+struct AckToken(String);
+
+impl fmt::Debug for AckToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
 }
 
+impl PreloaderMessage {
+    /// Returns the ID of the worker that the message is about, if any.
+    fn worker_id(&self) -> Option<&str> {
+        use PreloaderMessage::*;
+        use PreloaderSpecificMessage::*;
+        match self {
+            Preloader(Failed { id, .. }) | Preloader(Launched { id, .. }) => Some(id),
+            WorkerControl(msg) => Some(msg.id()),
+            _ => None,
+        }
+    }
+}
+
+/// A message from the preloader or one of its workers, along with
+/// the PID of the process that sent it, if known.
+type Received = (PreloaderMessage, Option<u32>);
+
+type MessageSender = Sender<Result<Received>>;
+
+/// Where messages from the preloader go: Messages about a worker go
+/// to the process control that spawned it, everything else goes to
+/// the process control that started the preloader.
+///
+/// Workers spawned by a shared handle have IDs of the form
+/// `<route>.<uuid>`, where `route` is the handle's index in `shared`.
+#[derive(Debug)]
+struct Routes {
+    owner: MessageSender,
+    shared: Vec<MessageSender>,
+}
+
+impl Routes {
+    fn for_message(&self, msg: &PreloaderMessage) -> &MessageSender {
+        msg.worker_id()
+            .and_then(|id| id.split_once('.'))
+            .and_then(|(route, _)| route.parse::<usize>().ok())
+            .and_then(|route| self.shared.get(route))
+            .unwrap_or(&self.owner)
+    }
+}
+
+/// A handle on a preloader process. The handle that started the
+/// preloader can be [shared](ProcessControl::share) with other worker
+/// groups, which then fork their workers off the same preloaded code.
 #[derive(Debug)]
 pub struct Preloader {
-    control_channel: ControlChannel,
+    writer: Arc<futures::lock::Mutex<ControlChannel>>,
+    routes: Arc<Mutex<Routes>>,
+    messages: Receiver<Result<Received>>,
+    route: Option<usize>,
+    /// The ack tokens of the workers that haven't acked yet, by ID.
+    tokens: HashMap<String, String>,
+    expression: Option<String>,
+    /// The capabilities that both sides support; `None` until the
+    /// preloader is ready.
+    capabilities: Option<Vec<String>>,
+    boot_timeout: Option<Duration>,
+    load_timeout: Option<Duration>,
+    last_request: u64,
     pid: u32,
 }
 
@@ -94,76 +228,335 @@ pub struct PreloaderLaunchFailure {
     message: String,
 }
 
-impl Preloader {
-    async fn send_message(&mut self, msg: &PreloaderRequest) -> Result<()> {
-        let mut msg = serde_json::to_vec(msg)?;
-        info!("sending"; "msg" => String::from_utf8(msg.clone()).unwrap());
-        msg.push(b'\n');
-        Duration::from_secs(0);
-        self.control_channel
-            .write_all(&msg)
-            .await
-            .context("Failed to send control message")?;
-        self.control_channel
-            .flush()
-            .await
-            .context("Could not flush control channel")?;
-        Ok(())
-    }
-
-    async fn next_preloader_message(&mut self) -> Result<PreloaderMessage> {
-        loop {
-            let mut line = String::new();
-            let count = self.control_channel.read_line(&mut line).await?;
-            if count == 0 {
+/// Reads messages off the preloader's control channel and routes them
+/// to the process control they are meant for, until the preloader
+/// dies.
+async fn read_messages(mut lines: ControlLines, routes: Arc<Mutex<Routes>>) {
+    loop {
+        let (msg, sender) = match lines.next_line().await {
+            Ok(None) => {
                 // Preloader has closed the connection. We assume it's dead.
                 debug!("read 0 bytes off the preloader pipe, it's dead");
-                return Err(PreloaderDied.into());
+                let routes = routes.lock();
+                for sender in Some(&routes.owner).into_iter().chain(routes.shared.iter()) {
+                    let _ = sender.try_send(Err(PreloaderDied.into()));
+                }
+                return;
             }
-            if let Some(msg) = logging::translate_message(serde_json::from_str(&line)?) {
-                return Ok(msg);
+            Ok(Some((line, sender))) => match serde_json::from_str(&line) {
+                Ok(msg) => match logging::translate_message(msg) {
+                    Some(msg) => (msg, sender),
+                    None => continue,
+                },
+                Err(e) => {
+                    let _ = routes.lock().owner.try_send(Err(e.into()));
+                    continue;
+                }
+            },
+            Err(e) => {
+                let _ = routes.lock().owner.try_send(Err(e));
+                continue;
             }
+        };
+        let route = routes.lock().for_message(&msg).clone();
+        // The receiving end only goes away when we exit:
+        let _ = route.send(Ok((msg, sender))).await;
+    }
+}
+
+/// Returns the earliest time by which a preloader that started at
+/// `started` has to make progress, along with the error to report if
+/// it doesn't: It has to be ready within `boot_timeout`, and finish
+/// loading the `current` file (loading since the given time) within
+/// `load_timeout`.
+pub fn boot_deadline(
+    started: Instant,
+    boot_timeout: Option<Duration>,
+    load_timeout: Option<Duration>,
+    current: Option<&(PathBuf, Instant)>,
+) -> Option<(Instant, String)> {
+    let boot = boot_timeout.map(|timeout| {
+        let error = match current {
+            Some((file, _)) => format!(
+                "The preloader did not get ready within {:?}; it was stuck loading {:?}",
+                timeout, file
+            ),
+            None => format!(
+                "The preloader did not get ready within {:?}, before loading any file",
+                timeout
+            ),
+        };
+        (started + timeout, error)
+    });
+    let load = load_timeout.and_then(|timeout| {
+        current.map(|(file, since)| {
+            let error = format!(
+                "The preloader took longer than {:?} to load {:?}",
+                timeout, file
+            );
+            (*since + timeout, error)
+        })
+    });
+    match (boot, load) {
+        (Some(boot), Some(load)) => Some(if load.0 < boot.0 { load } else { boot }),
+        (boot, load) => boot.or(load),
+    }
+}
+
+async fn send_request(
+    writer: &futures::lock::Mutex<ControlChannel>,
+    msg: &PreloaderRequest,
+) -> Result<()> {
+    info!("sending"; "msg" => ?msg);
+    let mut msg = serde_json::to_vec(msg)?;
+    msg.push(b'\n');
+    writer.lock().await.send(&msg).await
+}
+
+impl Preloader {
+    /// Starts routing messages from the preloader on
+    /// `control_channel`; returns the handle that owns the preloader.
+    fn new(control_channel: ControlChannel, pid: u32) -> Preloader {
+        let lines = control_channel.lines();
+        let (sender, messages) = unbounded();
+        let routes = Arc::new(Mutex::new(Routes {
+            owner: sender,
+            shared: vec![],
+        }));
+        Task::spawn(LogScoped::new(
+            slog_scope::logger(),
+            read_messages(lines, routes.clone()),
+        ))
+        .detach();
+        Preloader {
+            writer: Arc::new(futures::lock::Mutex::new(control_channel)),
+            routes,
+            messages,
+            route: None,
+            tokens: HashMap::new(),
+            expression: None,
+            capabilities: None,
+            boot_timeout: None,
+            load_timeout: None,
+            last_request: 0,
+            pid,
+        }
+    }
+
+    /// Limits how long the preloader may take to get ready in total
+    /// (`boot_timeout`), and to load any single file (`load_timeout`).
+    /// A preloader that takes longer gets killed.
+    pub(crate) fn with_timeouts(
+        mut self,
+        boot_timeout: Option<Duration>,
+        load_timeout: Option<Duration>,
+    ) -> Preloader {
+        self.boot_timeout = boot_timeout;
+        self.load_timeout = load_timeout;
+        self
+    }
+
+    /// Returns the earliest time by which the preloader has to make
+    /// progress in `state`, along with the error to report if it
+    /// doesn't.
+    fn deadline(&self, started: Instant, state: &PreloaderState) -> Option<(Instant, String)> {
+        let current = match state {
+            PreloaderState::Loading(loading) => loading.current(),
+            _ => None,
+        };
+        boot_deadline(started, self.boot_timeout, self.load_timeout, current)
+    }
+
+    /// Returns how many launched workers the preloader still holds an
+    /// ack token for.
+    pub fn pending_acks(&self) -> usize {
+        self.tokens.len()
+    }
+
+    async fn send_message(&mut self, msg: &PreloaderRequest) -> Result<()> {
+        send_request(&self.writer, msg).await
+    }
+
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities
+            .as_ref()
+            .map(|capabilities| capabilities.iter().any(|c| c == capability))
+            .unwrap_or(false)
+    }
+
+    async fn next_preloader_message(&mut self) -> Result<Received> {
+        match self.messages.recv().await {
+            Ok(msg) => msg,
+            Err(_) => Err(PreloaderDied.into()),
         }
     }
 }
 
 #[async_trait]
 impl ProcessControl for Preloader {
-    async fn initialize(&mut self) -> Result<()> {
+    async fn initialize(&mut self, on_loading: OnLoading<'_>) -> Result<()> {
+        if self.capabilities.is_some() {
+            return Ok(());
+        }
+        let started = Instant::now();
         let mut state = PreloaderState::starting();
         while let PreloaderState::Starting(_) | PreloaderState::Loading(_) = state {
-            let msg = self.next_preloader_message().await?;
+            let (msg, _) = match self.deadline(started, &state) {
+                None => self.next_preloader_message().await?,
+                Some((deadline, error)) => select! {
+                    msg = self.next_preloader_message().fuse() => msg?,
+                    _ = Timer::at(deadline).fuse() => {
+                        error!("killing the preloader"; "error" => &error, "pid" => self.pid);
+                        let _ = kill(Pid::from_raw(self.pid as i32), Signal::SIGKILL);
+                        bail!(error);
+                    }
+                },
+            };
+            if let PreloaderMessage::Preloader(PreloaderSpecificMessage::Loading { file }) = &msg {
+                on_loading(file);
+            }
             state = state.on_preloader_message(msg);
         }
         match state {
-            PreloaderState::Ready(_) => Ok(()),
+            PreloaderState::Ready(ready) => {
+                self.capabilities = Some(ready.into_capabilities());
+                Ok(())
+            }
+            PreloaderState::Failed(failed) => bail!("{}", failed.reason()),
             state => {
                 bail!("Unexpected preloader state {:?}", state);
             }
         }
     }
 
-    async fn spawn_process(&mut self) -> Result<String> {
-        let id = self.generate_id();
-        self.send_message(&PreloaderRequest::Spawn { id: id.to_string() })
-            .await?;
+    async fn spawn_process(&mut self, index: usize) -> Result<String> {
+        let id = match self.route {
+            Some(route) => format!("{}.{}", route, self.generate_id()),
+            None => self.generate_id(),
+        };
+        let token = if self.supports("ack_token") {
+            let token = self.generate_token();
+            self.tokens.insert(id.to_string(), token.to_string());
+            Some(token)
+        } else {
+            None
+        };
+        self.send_message(&PreloaderRequest::Spawn {
+            id: id.to_string(),
+            index,
+            expression: self.expression.clone(),
+            token: token.map(AckToken),
+        })
+        .await?;
         Ok(id)
     }
 
+    fn preloader_pid(&self) -> Option<Pid> {
+        Some(Pid::from_raw(self.pid as i32))
+    }
+
+    fn forget_worker(&mut self, id: &str) {
+        self.tokens.remove(id);
+    }
+
+    fn send_to_worker(&mut self, id: &str, op: WorkerOp) -> Result<BoxFuture<'static, Result<()>>> {
+        if !self.supports("requests") {
+            bail!("The preloader does not forward requests to workers; it needs to be updated");
+        }
+        self.last_request += 1;
+        let msg = PreloaderRequest::Forward {
+            id: id.to_string(),
+            request: WorkerRequest {
+                request: self.last_request,
+                op,
+            },
+        };
+        let writer = self.writer.clone();
+        Ok(async move { send_request(&writer, &msg).await }.boxed())
+    }
+
+    fn share(&self, expression: &str) -> Result<Box<dyn ProcessControl>> {
+        if self.capabilities.is_none() {
+            bail!("The preloader has not finished loading code yet");
+        }
+        if !self.supports("spawn_expression") {
+            bail!("The preloader does not support per-group start expressions; it needs to be updated");
+        }
+        let (sender, messages) = unbounded();
+        let route = {
+            let mut routes = self.routes.lock();
+            routes.shared.push(sender);
+            routes.shared.len() - 1
+        };
+        Ok(Box::new(Preloader {
+            writer: self.writer.clone(),
+            routes: self.routes.clone(),
+            messages,
+            route: Some(route),
+            tokens: HashMap::new(),
+            expression: Some(expression.to_string()),
+            capabilities: self.capabilities.clone(),
+            boot_timeout: None,
+            load_timeout: None,
+            last_request: 0,
+            pid: self.pid,
+        }))
+    }
+
     async fn next_message(&mut self) -> Result<Message> {
         use PreloaderMessage::*;
         use PreloaderSpecificMessage::*;
-        match self.next_preloader_message().await? {
-            Preloader(Launched { id, pid }) => Ok(Message::Launched { id, pid }),
-            Preloader(Failed { id, message }) => Ok(Message::LaunchError {
-                id,
-                error: PreloaderLaunchFailure { message }.into(),
-                pid: None,
-            }),
-            WorkerControl(WorkerControlMessage::Ack { id }) => Ok(Message::Ack { id }),
-            msg => {
-                bail!("Unexpected preloader message {:?}", msg);
-            }
+        loop {
+            return match self.next_preloader_message().await? {
+                (Preloader(Launched { id, pid }), _) => Ok(Message::Launched { id, pid }),
+                (Preloader(Failed { id, message }), _) => {
+                    self.tokens.remove(&id);
+                    Ok(Message::LaunchError {
+                        id,
+                        error: PreloaderLaunchFailure { message }.into(),
+                        pid: None,
+                    })
+                }
+                (WorkerControl(WorkerControlMessage::Ack { id, token, details }), sender) => {
+                    if self.supports("ack_token") {
+                        if token.is_none() || self.tokens.get(&id) != token.as_ref() {
+                            // Any process that inherited the channel
+                            // can send this; don't let it touch the
+                            // worker:
+                            warn!("ignoring an ack without the worker's ack token";
+                                  "worker_id" => &id, "sender_pid" => ?sender);
+                            continue;
+                        }
+                        self.tokens.remove(&id);
+                    }
+                    Ok(Message::Ack {
+                        id,
+                        details,
+                        sender,
+                    })
+                }
+                (WorkerControl(WorkerControlMessage::ExtendTimeout { id, by }), sender) => {
+                    Ok(Message::ExtendTimeout { id, by, sender })
+                }
+                (WorkerControl(WorkerControlMessage::Progress { id, message }), _) => {
+                    Ok(Message::Status {
+                        id,
+                        status: message,
+                    })
+                }
+                (WorkerControl(WorkerControlMessage::Unready { id, reason }), sender) => {
+                    Ok(Message::Unready { id, reason, sender })
+                }
+                (WorkerControl(WorkerControlMessage::Ready { id }), sender) => {
+                    Ok(Message::Ready { id, sender })
+                }
+                (WorkerControl(WorkerControlMessage::Reply { id, request, reply }), _) => {
+                    Ok(Message::Reply { id, request, reply })
+                }
+                (msg, _) => {
+                    bail!("Unexpected preloader message {:?}", msg);
+                }
+            };
         }
     }
 }
@@ -0,0 +1,346 @@
+"""The kleinhirn preloader for python applications.
+
+Imports the application module, then forks pre-loaded workers when
+the supervisor asks for them. It speaks the same JSONL protocol on
+the status FD as the ruby `kleinhirn_loader` gem.
+
+Workers confirm that they finished starting up with:
+
+    import kleinhirn
+    kleinhirn.done()
+
+While starting up, they can report what they are doing with
+`kleinhirn.progress(message)`, and ask for more time with
+`kleinhirn.extend_timeout(seconds)`. Afterwards, they can take
+themselves out of rotation with `kleinhirn.unready(reason)`, come
+back with `kleinhirn.ready()`, and handle requests from the supervisor
+with `kleinhirn.each_request(handler)`.
+"""
+
+import argparse
+import gc
+import importlib
+import json
+import os
+import random
+import socket
+import sys
+import traceback
+import types
+
+WORKER_ID_ENV = "KLEINHIRN_WORKER_ID"
+ACK_TOKEN_ENV = "KLEINHIRN_ACK_TOKEN"
+WORKER_INDEX_ENV = "KLEINHIRN_WORKER_INDEX"
+NAME_ENV = "KLEINHIRN_NAME"
+VERSION_ENV = "KLEINHIRN_VERSION"
+STATUS_FD_ENV = "KLEINHIRN_STATUS_FD"
+REQUESTS_FD_ENV = "KLEINHIRN_REQUESTS_FD"
+CAPABILITIES_ENV = "KLEINHIRN_CAPABILITIES"
+
+# The version of the supervisor protocol that this loader speaks, and
+# the optional protocol features it supports.
+PROTOCOL_VERSION = 1
+CAPABILITIES = ["spawn_expression", "ack_token", "requests"]
+
+
+class Loader:
+    def __init__(self, name, version, start_callable, status_fd):
+        self.name = name
+        self.version = version
+        self.start_callable = start_callable
+        self.status_fd = status_fd
+        self.status_io = socket.socket(fileno=status_fd).makefile("rw", buffering=1)
+        self.worker_ids = set()
+        # The sockets that requests to the workers go to, by worker ID.
+        self.request_sockets = {}
+        self.module = None
+        self.capabilities = set()
+
+    def send(self, reply):
+        self.status_io.write(json.dumps(reply) + "\n")
+        self.status_io.flush()
+
+    def log(self, level, msg, **fields):
+        reply = {"action": "log", "level": level, "msg": msg}
+        reply.update({key: str(value) for key, value in fields.items()})
+        self.send(reply)
+
+    def hello(self):
+        """Introduces the loader to the supervisor, and records which
+        optional protocol features both sides support."""
+        theirs = os.environ.pop(CAPABILITIES_ENV, "").split(",")
+        self.capabilities = set(CAPABILITIES) & set(theirs)
+        self.send({"action": "hello", "protocol_version": PROTOCOL_VERSION, "capabilities": CAPABILITIES})
+
+    def load(self, module):
+        """Imports the application and prepares the heap for forking."""
+        set_proctitle("%s/%s kleinhirn_loader loading %s" % (self.name, self.version, module))
+        self.send({"action": "loading", "file": module})
+        self.module = importlib.import_module(module)
+        # Keep the loaded objects out of future collections, so the
+        # GC doesn't touch (and copy) their pages in the workers:
+        gc.collect()
+        if hasattr(gc, "freeze"):
+            gc.freeze()
+
+    def repl(self):
+        """Runs the command loop until the supervisor goes away."""
+        set_proctitle("%s/%s kleinhirn_loader" % (self.name, self.version))
+        os.setpgrp()
+        self.send({"action": "ready"})
+        for line in self.status_io:
+            try:
+                command = json.loads(line)
+                if command.get("op") not in ("spawn", "forward") or "id" not in command:
+                    raise ValueError("unknown command %r" % line)
+                if command["op"] == "forward" and not isinstance(command.get("request"), dict):
+                    raise ValueError("forward without a request object: %r" % line)
+            except ValueError as e:
+                self.send({"action": "error", "message": "in command processing", "error": str(e)})
+                continue
+            child_id = command["id"]
+            if command["op"] == "forward":
+                self.forward(child_id, command["request"])
+                continue
+            if child_id in self.worker_ids:
+                self.send({"action": "failed", "id": child_id, "message": "duplicate ID"})
+                continue
+            self.fork_one(child_id, command.get("index"), command.get("expression") or self.start_callable,
+                          command.get("token"))
+            self.worker_ids.add(child_id)
+        sys.exit(0)
+
+    def forward(self, child_id, request):
+        """Writes `request` to the socket of the worker `child_id`,
+        without waiting for the worker to read it. Sockets of workers
+        that are gone (or don't keep up) get closed."""
+        self.prune_request_sockets()
+        sock = self.request_sockets.get(child_id)
+        if sock is None:
+            self.log("info", "no request socket for worker", worker_id=child_id)
+            return
+        try:
+            sock.send((json.dumps(request) + "\n").encode(), socket.MSG_DONTWAIT)
+        except OSError as e:
+            self.log("info", "could not forward request to worker", worker_id=child_id, error=e)
+            self.request_sockets.pop(child_id).close()
+
+    def prune_request_sockets(self):
+        """Closes the request sockets of workers that have exited.
+        Workers never write to their request socket, so it only
+        becomes readable once they close it."""
+        for child_id, sock in list(self.request_sockets.items()):
+            try:
+                if sock.recv(1, socket.MSG_DONTWAIT) != b"":
+                    continue
+            except BlockingIOError:
+                continue
+            except OSError:
+                pass
+            self.request_sockets.pop(child_id).close()
+
+    def fork_one(self, child_id, index, start_callable, token=None):
+        """Double-forks one pre-loaded worker process that calls
+        `start_callable`. The direct child gets re-parented to the
+        supervisor when it exits. If the supervisor sends requests,
+        the worker gets a socket that the loader forwards them to."""
+        ours, theirs = socket.socketpair() if "requests" in self.capabilities else (None, None)
+        pid = os.fork()
+        if pid:
+            if theirs is not None:
+                theirs.close()
+            _, status = os.waitpid(pid, 0)
+            if status != 0:
+                if ours is not None:
+                    ours.close()
+                self.send({"action": "failed", "id": child_id, "message": "non-zero exit"})
+            elif ours is not None:
+                self.prune_request_sockets()
+                self.request_sockets[child_id] = ours
+            return
+
+        try:
+            if ours is not None:
+                ours.close()
+            for sock in self.request_sockets.values():
+                sock.close()
+            self.request_sockets.clear()
+            if theirs is not None:
+                os.environ[REQUESTS_FD_ENV] = str(theirs.detach())
+            self.setup_child_environment(child_id, index, token)
+            pid = os.fork()
+            if pid:
+                self.send({"action": "launched", "id": child_id, "pid": pid})
+                os._exit(0)
+        except BaseException:
+            os._exit(1)
+
+        # Now we're in the worker - start it up.
+        status = 0
+        try:
+            if "LISTEN_FDS" in os.environ:
+                os.environ["LISTEN_PID"] = str(os.getpid())
+            set_proctitle("%s/%s kleinhirn worker %s %s - startup" % (self.name, self.version, index, child_id))
+            self.log("info", "worker starting", child_id=child_id, worker_index=index, pid=os.getpid())
+            getattr(self.module, start_callable)()
+        except SystemExit as e:
+            status = e.code if isinstance(e.code, int) else 1
+        except BaseException:
+            self.log("info", "worker failed", child_id=child_id, error=traceback.format_exc())
+            status = 1
+        finally:
+            sys.stdout.flush()
+            sys.stderr.flush()
+            os._exit(status)
+
+    def setup_child_environment(self, child_id, index, token):
+        os.chdir("/")
+        random.seed()
+        os.environ[WORKER_ID_ENV] = child_id
+        if token is not None:
+            os.environ[ACK_TOKEN_ENV] = token
+        if index is not None:
+            os.environ[WORKER_INDEX_ENV] = str(index)
+        os.environ[NAME_ENV] = self.name
+        os.environ[VERSION_ENV] = self.version
+        os.environ[STATUS_FD_ENV] = str(self.status_fd)
+
+
+# The status FD, requests FD and ID of this worker, kept around after
+# `done` removes them from the environment.
+worker = {}
+
+
+def done(version=None, build_info=None):
+    """Confirms to the supervisor that the worker finished starting up.
+
+    `version` names the version of the code that the worker loaded;
+    the supervisor refuses workers whose version differs from the one
+    it expects. `build_info` (a dict) gets logged."""
+    ack = {"action": "ack"}
+    token = os.environ.get(ACK_TOKEN_ENV)
+    if token is not None:
+        ack["token"] = token
+    if version is not None:
+        ack["version"] = version
+    if build_info is not None:
+        ack["build_info"] = build_info
+    if not send_to_supervisor(ack):
+        return
+    set_proctitle("%s/%s kleinhirn worker %s %s" % (
+        os.environ.get(NAME_ENV), os.environ.get(VERSION_ENV),
+        os.environ.get(WORKER_INDEX_ENV), worker["id"]))
+    for var in (WORKER_ID_ENV, ACK_TOKEN_ENV, WORKER_INDEX_ENV, NAME_ENV, VERSION_ENV, STATUS_FD_ENV,
+                REQUESTS_FD_ENV):
+        os.environ.pop(var, None)
+
+
+def extend_timeout(seconds):
+    """Asks the supervisor for `seconds` more time (counted from now)
+    to finish starting up, up to its configured `max_ack_timeout`."""
+    send_to_supervisor({"action": "extend_timeout", "by": "%dms" % round(seconds * 1000)})
+
+
+def progress(message):
+    """Tells the supervisor what the worker is busy with while it
+    starts up; it shows up in the supervisor's status."""
+    send_to_supervisor({"action": "progress", "message": message})
+
+
+def unready(reason):
+    """Takes the worker out of rotation without stopping it, say
+    because it lost its database connection: The supervisor counts it
+    as not ready, for `reason`, until it calls `ready()`."""
+    send_to_supervisor({"action": "unready", "reason": reason})
+
+
+def ready():
+    """Puts the worker back into rotation after `unready()`."""
+    send_to_supervisor({"action": "ready"})
+
+
+def each_request(handler):
+    """Calls `handler` with each request that the supervisor sends (a
+    dict with the `request` number, the `op` - `drain`, `reopen_logs`,
+    `dump_status` or `custom` - and the `command` of custom ones), and
+    answers it with the handler's result unless that is None. Returns
+    once the supervisor (or the loader) closes the channel, or right
+    away if the worker gets no requests."""
+    remember_worker()
+    if "requests_fd" not in worker:
+        return
+    os.set_inheritable(worker["requests_fd"], False)
+    requests_io = socket.socket(fileno=worker["requests_fd"])
+    try:
+        for line in requests_io.makefile("r"):
+            request = json.loads(line)
+            reply = handler(request)
+            if reply is not None:
+                send_to_supervisor({"action": "reply", "request": request.get("request"), "reply": reply})
+    finally:
+        requests_io.detach()
+
+
+def remember_worker():
+    """Keeps this worker's FDs and ID around after `done` removes them
+    from the environment."""
+    if STATUS_FD_ENV in os.environ and WORKER_ID_ENV in os.environ:
+        worker["fd"] = int(os.environ[STATUS_FD_ENV])
+        worker["id"] = os.environ[WORKER_ID_ENV]
+    if REQUESTS_FD_ENV in os.environ:
+        worker["requests_fd"] = int(os.environ[REQUESTS_FD_ENV])
+
+
+def send_to_supervisor(reply):
+    """Sends `reply` on the status FD, keeping it open (but out of
+    reach of programs that the worker runs). Returns False outside of
+    workers."""
+    remember_worker()
+    if "id" not in worker:
+        return False
+    reply["id"] = worker["id"]
+    os.set_inheritable(worker["fd"], False)
+    status_io = socket.socket(fileno=worker["fd"])
+    try:
+        status_io.sendall((json.dumps(reply) + "\n").encode())
+    finally:
+        status_io.detach()
+    return True
+
+
+def set_proctitle(title):
+    try:
+        import setproctitle
+        setproctitle.setproctitle(title)
+    except ImportError:
+        pass
+
+
+def main(args):
+    parser = argparse.ArgumentParser(prog="kleinhirn_loader")
+    parser.add_argument("--status-fd", type=int, required=True)
+    parser.add_argument("--name", default=os.path.basename(os.getcwd()))
+    parser.add_argument("--code-version", default=None)
+    parser.add_argument("--module", required=True)
+    parser.add_argument("--start-callable", required=True)
+    options = parser.parse_args(args)
+
+    # Let the application `import kleinhirn` to ack:
+    kleinhirn = types.ModuleType("kleinhirn")
+    kleinhirn.done = done
+    kleinhirn.extend_timeout = extend_timeout
+    kleinhirn.progress = progress
+    kleinhirn.unready = unready
+    kleinhirn.ready = ready
+    kleinhirn.each_request = each_request
+    sys.modules["kleinhirn"] = kleinhirn
+
+    version = options.code_version or "%032x" % random.getrandbits(128)
+    loader = Loader(options.name, version, options.start_callable, options.status_fd)
+    loader.hello()
+    loader.load(options.module)
+    loader.repl()
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])
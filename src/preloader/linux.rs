@@ -1,40 +1,161 @@
 #![cfg(target_os = "linux")]
 
 use super::Preloader;
-use crate::worker_ack;
+use crate::{configuration, fork_exec, worker_ack};
 use anyhow::{Context, Result};
 use slog_scope::debug;
-use std::{path::Path, process::Command};
+use std::{env, path::Path, process::Command};
+
+/// The preloader script for python applications.
+const PYTHON_LOADER: &str = include_str!("kleinhirn_loader.py");
+
+/// The environment variable that holds flags for every ruby
+/// interpreter.
+const RUBYOPT_ENV: &str = "RUBYOPT";
 
 impl Preloader {
-    /// Constructs the ruby preloader, starts it and waits until the code is loaded.
-    pub fn for_ruby(gemfile: &Path, load: &Path, start_expression: &str) -> Result<Preloader> {
+    /// Constructs the ruby preloader and starts it. Paths in `ruby`
+    /// must already be resolved relative to the configuration file.
+    pub fn for_ruby(
+        name: &str,
+        version: Option<&str>,
+        ruby: &configuration::Ruby,
+    ) -> Result<Preloader> {
+        Preloader::spawn(|status_fd| {
+            let mut cmd = match &ruby.gemfile {
+                Some(gemfile) => {
+                    let mut cmd = Command::new("bundle");
+                    cmd.args(["exec", "--gemfile"])
+                        .arg(gemfile.as_os_str())
+                        .args(["--keep-file-descriptors", "--", "kleinhirn_loader"]);
+                    cmd
+                }
+                None => Command::new("kleinhirn_loader"),
+            };
+            cmd.args(["--status-fd", status_fd, "--name", name])
+                .args(["-e", &ruby.start_expression]);
+            if let Some(version) = version {
+                cmd.args(["--code-version", version]);
+            }
+            for load in ruby.load.iter() {
+                cmd.arg("-r").arg(load.as_os_str());
+            }
+            cmd.envs(&ruby.env);
+            if !ruby.ruby_flags.is_empty() {
+                let rubyopt = ruby
+                    .env
+                    .get(RUBYOPT_ENV)
+                    .cloned()
+                    .or_else(|| env::var(RUBYOPT_ENV).ok())
+                    .into_iter()
+                    .chain(ruby.ruby_flags.iter().cloned())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                cmd.env(RUBYOPT_ENV, rubyopt);
+            }
+            if let Some(cwd) = &ruby.cwd {
+                cmd.current_dir(cwd);
+            }
+            Ok(cmd)
+        })
+    }
+
+    /// Constructs the python preloader, which imports `module` and
+    /// starts workers by calling `start_callable` in it.
+    pub fn for_python(
+        name: &str,
+        version: Option<&str>,
+        python: &Path,
+        module: &str,
+        start_callable: &str,
+        cwd: &Path,
+    ) -> Result<Preloader> {
+        Preloader::spawn(|status_fd| {
+            Ok(Preloader::python_command(
+                name,
+                version,
+                python,
+                module,
+                start_callable,
+                cwd,
+                status_fd,
+            ))
+        })
+    }
+
+    /// Returns the command that [`for_python`](Self::for_python)
+    /// runs, talking to the supervisor on `status_fd`.
+    pub fn python_command(
+        name: &str,
+        version: Option<&str>,
+        python: &Path,
+        module: &str,
+        start_callable: &str,
+        cwd: &Path,
+        status_fd: &str,
+    ) -> Command {
+        let mut cmd = Command::new(python);
+        cmd.args(["-c", PYTHON_LOADER])
+            .args(["--status-fd", status_fd, "--name", name])
+            .args(["--module", module, "--start-callable", start_callable])
+            .current_dir(cwd);
+        if let Some(version) = version {
+            cmd.args(["--code-version", version]);
+        }
+        cmd
+    }
+
+    /// Constructs a preloader from any program that speaks the
+    /// preloader protocol on the FD named in `$KLEINHIRN_STATUS_FD`,
+    /// and starts it.
+    pub fn for_program(
+        name: &str,
+        version: Option<&str>,
+        program: &configuration::PreloaderProgram,
+        cwd: Option<&Path>,
+    ) -> Result<Preloader> {
+        Preloader::spawn(|status_fd| {
+            Preloader::program_command(name, version, program, cwd, status_fd)
+        })
+    }
+
+    /// Returns the command that [`for_program`](Self::for_program)
+    /// runs, talking to the supervisor on `status_fd`.
+    pub fn program_command(
+        name: &str,
+        version: Option<&str>,
+        program: &configuration::PreloaderProgram,
+        cwd: Option<&Path>,
+        status_fd: &str,
+    ) -> Result<Command> {
+        let mut args = program.cmdline.iter();
+        let mut cmd = Command::new(args.next().context("no commandline given")?);
+        cmd.args(args)
+            .envs(&program.env)
+            .env(fork_exec::WORKER_CONTROL_CHANNEL_ENV, status_fd)
+            .env(fork_exec::NAME_ENV, name);
+        if let Some(version) = version {
+            cmd.env(fork_exec::VERSION_ENV, version);
+        }
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        Ok(cmd)
+    }
+
+    /// Runs the preloader command that `command` builds for the given
+    /// status FD number, telling it which capabilities we support.
+    fn spawn(command: impl FnOnce(&str) -> Result<Command>) -> Result<Preloader> {
         prctl::set_child_subreaper(true)
             .map_err(|code| anyhow::anyhow!("Unable to set subreaper status. Status {:?}", code))?;
         let (their_fd, control_channel) = worker_ack::worker_status_stream()
             .context("Failed to make a preloader control channel")?;
-        let theirs_str = their_fd.to_string();
-        let mut cmd = Command::new("bundle");
-        cmd.args(&["exec", "--gemfile"])
-            .arg(gemfile.as_os_str())
-            .args(&[
-                "--keep-file-descriptors",
-                "--",
-                "kleinhirn_loader",
-                "--status-fd",
-                &theirs_str,
-                "-e",
-                start_expression,
-                "-r",
-            ])
-            .arg(load.as_os_str());
+        let mut cmd = command(&their_fd.to_string())?;
+        cmd.env(super::CAPABILITIES_ENV, super::CAPABILITIES.join(","));
         debug!("running preloader"; "cmd" => ?cmd);
-        let child = cmd.spawn().context("spawning kleinhirn_loader")?;
+        let child = cmd.spawn().context("spawning the preloader")?;
         debug!("child running"; "pid" => ?child.id());
 
-        Ok(Preloader {
-            control_channel,
-            pid: child.id(),
-        })
+        Ok(Preloader::new(control_channel, child.id()))
     }
 }
@@ -1,20 +1,28 @@
-use super::{PreloaderMessage, PreloaderSpecificMessage};
+use super::{negotiate, PreloaderMessage, PreloaderSpecificMessage, PROTOCOL_VERSION};
 use machine::*;
-use slog_scope::{debug, error, warn};
+use slog_scope::{debug, error, info, warn};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 machine! {
     #[derive(Clone, PartialEq, Debug)]
     pub enum PreloaderState {
         Starting,
-        Loading,
-        Ready,
-        Failed,
+        Loading {
+            capabilities: Vec<String>,
+            current: Option<(PathBuf, Instant)>,
+            loaded: Vec<(PathBuf, Duration)>,
+        },
+        Ready { capabilities: Vec<String> },
+        Failed { reason: String },
     }
 }
 
 transitions!(PreloaderState, [
-    (Starting, PreloaderMessage) => [Starting, Loading, Error],
-    (Loading, PreloaderMessage) => [Loading, Ready, Error]
+    (Starting, PreloaderMessage) => [Starting, Loading, Failed],
+    (Loading, PreloaderMessage) => [Loading, Ready, Failed]
 ]);
 
 impl Starting {
@@ -23,11 +31,24 @@ impl Starting {
         use PreloaderSpecificMessage::*;
 
         match msg {
-            Preloader(Loading { file }) => {
-                debug!("loading"; "file" => ?file);
-                PreloaderState::loading()
+            Preloader(Hello {
+                protocol_version,
+                capabilities,
+            }) => {
+                if protocol_version != PROTOCOL_VERSION {
+                    return PreloaderState::failed(format!(
+                        "The preloader speaks protocol version {}, but kleinhirn only supports version {}. Update the preloader (e.g. the kleinhirn_loader gem) to match this kleinhirn release.",
+                        protocol_version, PROTOCOL_VERSION
+                    ));
+                }
+                let capabilities = negotiate(&capabilities);
+                info!("preloader connected"; "protocol_version" => protocol_version, "capabilities" => ?capabilities);
+                PreloaderState::loading(capabilities, None, vec![])
             }
-            _ => PreloaderState::failed(),
+            msg => PreloaderState::failed(format!(
+                "The preloader did not start with a hello message, but sent {:?}. It is probably too old for this kleinhirn release (e.g. an outdated kleinhirn_loader gem).",
+                msg
+            )),
         }
     }
 }
@@ -38,20 +59,73 @@ impl Loading {
         use PreloaderSpecificMessage::*;
 
         match msg {
-            Preloader(Loading { .. }) => PreloaderState::loading(),
+            Preloader(Loading { file }) => {
+                debug!("loading"; "file" => ?file);
+                let loaded = self.finish_current();
+                PreloaderState::loading(self.capabilities, Some((file, Instant::now())), loaded)
+            }
             Preloader(Ready) => {
-                debug!("Preloader is ready");
-                PreloaderState::ready()
+                let loaded = self.finish_current();
+                let total: Duration = loaded.iter().map(|(_, took)| *took).sum();
+                let profile: Vec<String> = loaded
+                    .iter()
+                    .map(|(file, took)| format!("{}: {:?}", file.display(), took))
+                    .collect();
+                let slowest = loaded
+                    .iter()
+                    .max_by_key(|(_, took)| *took)
+                    .map(|(file, took)| format!("{}: {:?}", file.display(), took))
+                    .unwrap_or_default();
+                info!("Preloader is ready";
+                      "load_time" => ?total,
+                      "slowest" => slowest,
+                      "boot_profile" => profile.join(", "));
+                PreloaderState::ready(self.capabilities)
             }
             Preloader(Error { message, error }) => {
                 error!("Communication error with the preloader. This is a bug."; "message" => %message, "error" => ?error);
-                PreloaderState::failed()
+                PreloaderState::failed(format!(
+                    "Communication error with the preloader: {}",
+                    message
+                ))
             }
             Preloader(Failed { id, message }) => {
-                warn!("Command failed"; "id" => id, "message" => %message);
-                PreloaderState::failed()
+                warn!("Command failed"; "id" => &id, "message" => %message);
+                PreloaderState::failed(format!("Command {} failed: {}", id, message))
             }
-            _ => PreloaderState::loading(),
+            _ => PreloaderState::loading(self.capabilities, self.current, self.loaded),
         }
     }
+
+    /// Returns the files loaded so far, including the current one
+    /// with the time it took until now.
+    fn finish_current(&self) -> Vec<(PathBuf, Duration)> {
+        let mut loaded = self.loaded.clone();
+        if let Some((file, since)) = &self.current {
+            loaded.push((file.clone(), since.elapsed()));
+        }
+        loaded
+    }
+}
+
+impl Loading {
+    /// The file that the preloader is loading, and since when.
+    pub fn current(&self) -> Option<&(PathBuf, Instant)> {
+        self.current.as_ref()
+    }
+}
+
+impl Ready {
+    /// The capabilities that both the supervisor and the preloader
+    /// support.
+    pub fn into_capabilities(self) -> Vec<String> {
+        self.capabilities
+    }
+}
+
+impl Failed {
+    /// Why the preloader could not get ready.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
 }
@@ -0,0 +1,170 @@
+//! Probes that tell whether a worker that can't speak any ack
+//! protocol is ready.
+
+use crate::{configuration::AckStrategy, template};
+use anyhow::{bail, Context, Result};
+use futures::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    select, FutureExt,
+};
+use http::Uri;
+use nix::{sys::signal::kill, unistd::Pid};
+use slog_scope::debug;
+use smol::{Async, Timer};
+use std::{
+    net::TcpStream,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// How long a single probe attempt may take: A worker that accepts
+/// the connection but never answers counts as not ready yet.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A probe with all variables substituted for one worker.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Probe {
+    Tcp {
+        addr: String,
+    },
+    Http {
+        addr: String,
+        host: String,
+        path: String,
+    },
+    File {
+        path: PathBuf,
+    },
+    Delay,
+}
+
+impl Probe {
+    /// Returns the probe for `strategy`, along with the interval to
+    /// wait between attempts. Strategies that don't probe return
+    /// `None`.
+    pub(crate) fn for_strategy(
+        strategy: &AckStrategy,
+        vars: &template::Variables,
+    ) -> Result<Option<(Probe, Duration)>> {
+        Ok(Some(match strategy {
+            AckStrategy::Immediate | AckStrategy::StatusFd | AckStrategy::Notify => {
+                return Ok(None)
+            }
+            AckStrategy::Tcp {
+                host,
+                port,
+                interval,
+            } => {
+                let addr = format!("{}:{}", vars.expand(host)?, vars.expand(port)?);
+                (Probe::Tcp { addr }, *interval)
+            }
+            AckStrategy::Http { url, interval } => {
+                let url = vars.expand(url)?;
+                let uri: Uri = url
+                    .parse()
+                    .with_context(|| format!("Parsing probe URL {:?}", url))?;
+                if uri.scheme_str() != Some("http") {
+                    bail!("Only http:// URLs can be probed, not {:?}", url);
+                }
+                let host = uri.host().context("Probe URL has no host")?;
+                let addr = format!("{}:{}", host, uri.port_u16().unwrap_or(80));
+                let path = uri
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/")
+                    .to_string();
+                let host = match uri.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                };
+                (Probe::Http { addr, host, path }, *interval)
+            }
+            AckStrategy::File { path, interval } => {
+                let path = PathBuf::from(vars.expand(&path.to_string_lossy())?);
+                (Probe::File { path }, *interval)
+            }
+            AckStrategy::Delay { delay } => (Probe::Delay, *delay),
+        }))
+    }
+
+    /// Returns true if the worker is ready.
+    async fn check(&self) -> bool {
+        match self {
+            Probe::Tcp { addr } => Async::<TcpStream>::connect(addr).await.is_ok(),
+            Probe::Http { addr, host, path } => match http_get_status(addr, host, path).await {
+                Ok(status) => (200..300).contains(&status),
+                Err(e) => {
+                    debug!("http probe failed"; "addr" => addr, "path" => path, "error" => ?e);
+                    false
+                }
+            },
+            Probe::File { path } => path.exists(),
+            Probe::Delay => true,
+        }
+    }
+
+    /// Resolves once the probe succeeds, waiting `interval` between
+    /// attempts. Returns false if the worker process `pid` goes away
+    /// or the `deadline` passes first.
+    pub(crate) async fn wait(
+        &self,
+        interval: Duration,
+        pid: u32,
+        deadline: Option<Instant>,
+    ) -> bool {
+        let alive = || kill(Pid::from_raw(pid as i32), None).is_ok();
+        if let Probe::Delay = self {
+            // The interval is the delay:
+            Timer::after(interval).await;
+            return alive();
+        }
+        loop {
+            let ready = select! {
+                ready = self.check().fuse() => ready,
+                _ = Timer::after(ATTEMPT_TIMEOUT).fuse() => {
+                    debug!("probe attempt timed out"; "probe" => ?self, "pid" => pid, "timeout" => ?ATTEMPT_TIMEOUT);
+                    false
+                }
+            };
+            if ready {
+                return true;
+            }
+            if deadline.map(|deadline| deadline < Instant::now()) == Some(true) {
+                debug!("giving up probing worker"; "probe" => ?self, "pid" => pid);
+                return false;
+            }
+            if !alive() {
+                debug!("worker exited while probing"; "probe" => ?self, "pid" => pid);
+                return false;
+            }
+            Timer::after(interval).await;
+        }
+    }
+}
+
+/// Sends a minimal HTTP/1.0 GET request and returns the response's
+/// status code.
+async fn http_get_status(addr: &str, host: &str, path: &str) -> Result<u16> {
+    let mut stream = Async::<TcpStream>::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: kleinhirn\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    let mut buf = [0u8; 64];
+    // The status line is all we need:
+    while !response.contains(&b'\n') {
+        let count = stream.read(&mut buf).await?;
+        if count == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..count]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .context("Malformed HTTP response")?;
+    status.parse().context("Malformed HTTP status")
+}
@@ -1,7 +1,15 @@
-use anyhow::Result;
+use crate::worker_ack::{AckDetails, WorkerOp};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use nix::unistd::Pid;
+use std::{path::Path, time::Duration};
 use uuid::Uuid;
 
+/// Gets called with each file that a process control starts loading
+/// while it initializes.
+pub type OnLoading<'a> = &'a (dyn Fn(&Path) + Send + Sync);
+
 /// A message that updates the supervisor on the state of a child
 /// process.
 #[derive(Debug)]
@@ -12,6 +20,45 @@ pub enum Message {
     },
     Ack {
         id: String,
+        details: AckDetails,
+        /// The PID of the process that sent the ack, if known.
+        sender: Option<u32>,
+    },
+    /// The worker asked for its ack deadline to be moved to `by` from
+    /// now.
+    ExtendTimeout {
+        id: String,
+        by: Duration,
+        /// The PID of the process that sent the request, if known.
+        sender: Option<u32>,
+    },
+    /// The worker can't take work right now, for the given reason.
+    Unready {
+        id: String,
+        reason: String,
+        /// The PID of the process that sent the report, if known.
+        sender: Option<u32>,
+    },
+    /// The worker can take work again.
+    Ready {
+        id: String,
+        /// The PID of the process that sent the report, if known.
+        sender: Option<u32>,
+    },
+    /// The worker reported a human-readable status.
+    Status {
+        id: String,
+        status: String,
+    },
+    /// The worker answered the request numbered `request`.
+    Reply {
+        id: String,
+        request: u64,
+        reply: serde_json::Value,
+    },
+    /// The worker let us know that it is still alive.
+    Alive {
+        id: String,
     },
     LaunchError {
         id: String,
@@ -21,15 +68,17 @@ pub enum Message {
 }
 
 #[async_trait]
-pub trait ProcessControl {
+pub trait ProcessControl: Send {
     /// Returns success when the process controller is
     /// initialized. This is a no-op on regular programs, but a
-    /// preloader will resolve here when the code is loaded.
-    async fn initialize(&mut self) -> Result<()>;
+    /// preloader will resolve here when the code is loaded, reporting
+    /// each file it loads to `on_loading`.
+    async fn initialize(&mut self, on_loading: OnLoading<'_>) -> Result<()>;
 
     /// Generates a child ID, spawns the process (probably forking, or
-    /// double-forking), and returns that ID on success.
-    async fn spawn_process(&mut self) -> Result<String>;
+    /// double-forking) into the worker slot `index`, and returns that
+    /// ID on success.
+    async fn spawn_process(&mut self, index: usize) -> Result<String>;
 
     /// Returns the next update of the process control scheme.
     ///
@@ -37,8 +86,43 @@ pub trait ProcessControl {
     /// broken down.
     async fn next_message(&mut self) -> Result<Message>;
 
+    /// Returns a process control that spawns workers off the same
+    /// (initialized) process control, starting them with a different
+    /// `expression`. Only preloaders support this.
+    fn share(&self, _expression: &str) -> Result<Box<dyn ProcessControl>> {
+        bail!("Only preloaded workers can be shared")
+    }
+
+    /// Returns the PID of the process that workers get forked off, if
+    /// there is one. The worker set can't work once it dies.
+    fn preloader_pid(&self) -> Option<Pid> {
+        None
+    }
+
+    /// Forgets about the worker `id`, which got reaped, or which the
+    /// supervisor gave up on before it acked.
+    fn forget_worker(&mut self, _id: &str) {}
+
+    /// Returns a future that sends `op` to the worker with the ID
+    /// `id`, or an error if the worker can't receive requests. The
+    /// future doesn't borrow the process control, so the supervisor
+    /// can send requests without waiting for them to go out.
+    fn send_to_worker(
+        &mut self,
+        _id: &str,
+        _op: WorkerOp,
+    ) -> Result<BoxFuture<'static, Result<()>>> {
+        bail!("These workers can't receive requests")
+    }
+
     /// Generates a UUID-based ID string.
     fn generate_id(&self) -> String {
         Uuid::new_v4().to_string()
     }
+
+    /// Generates an unguessable token that a worker has to present
+    /// when it acks.
+    fn generate_token(&self) -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
 }
@@ -0,0 +1,194 @@
+//! Telling a service manager like systemd about the supervisor's
+//! state, using the [`sd_notify`] protocol.
+//!
+//! [`sd_notify`]: https://www.freedesktop.org/software/systemd/man/sd_notify.html
+
+use anyhow::{Context, Result};
+use std::{env, os::unix::net::UnixDatagram, time::Duration};
+
+/// The environment variable that names the service manager's
+/// notification socket.
+pub const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// The environment variable that holds the watchdog interval in
+/// microseconds.
+pub const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+
+/// The environment variable that names the process that the watchdog
+/// is meant for.
+pub const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// A connection to a service manager's notification socket.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connects to the socket named in `$NOTIFY_SOCKET`, if that is
+    /// set. Like `sd_notify` with `unset_environment`, this removes the
+    /// notification variables from the environment, so that child
+    /// processes can't pose as the supervisor.
+    pub fn from_env() -> Result<Option<Notifier>> {
+        let path = env::var(NOTIFY_SOCKET_ENV);
+        let watchdog = watchdog_interval();
+        for var in &[NOTIFY_SOCKET_ENV, WATCHDOG_USEC_ENV, WATCHDOG_PID_ENV] {
+            env::remove_var(var);
+        }
+        match path {
+            Ok(path) => Ok(Some(Notifier {
+                watchdog,
+                ..Notifier::connect(&path)?
+            })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Connects to the notification socket at `path`. Paths that
+    /// start with `@` are in the abstract socket namespace.
+    pub fn connect(path: &str) -> Result<Notifier> {
+        let socket = UnixDatagram::unbound().context("Could not create a notification socket")?;
+        if let Some(name) = path.strip_prefix('@') {
+            connect_abstract(&socket, name)?;
+        } else {
+            socket
+                .connect(path)
+                .with_context(|| format!("Could not connect to notification socket {:?}", path))?;
+        }
+        Ok(Notifier {
+            socket,
+            watchdog: None,
+        })
+    }
+
+    /// Sends `state`, which consists of newline-separated
+    /// `VARIABLE=value` assignments.
+    pub fn notify(&self, state: &str) -> Result<()> {
+        self.socket
+            .send(state.as_bytes())
+            .context("Could not send a notification")?;
+        Ok(())
+    }
+
+    /// Returns how often the service manager expects `WATCHDOG=1`
+    /// notifications, as read from the environment by
+    /// [`from_env`](Self::from_env).
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Tells the service manager that startup is finished.
+    pub fn ready(&self) -> Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// Updates the free-form status text of the service.
+    pub fn status(&self, status: &str) -> Result<()> {
+        // Newlines would start a new assignment:
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+
+    /// Tells the service manager that the service is still alive.
+    pub fn watchdog(&self) -> Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    /// Tells the service manager that the service is shutting down.
+    pub fn stopping(&self) -> Result<()> {
+        self.notify("STOPPING=1")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(socket: &UnixDatagram, name: &str) -> Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    socket
+        .connect_addr(&addr)
+        .with_context(|| format!("Could not connect to notification socket @{:?}", name))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_socket: &UnixDatagram, name: &str) -> Result<()> {
+    anyhow::bail!("Abstract notification socket @{:?} is not supported", name)
+}
+
+/// Returns how often the service manager expects `WATCHDOG=1`
+/// notifications from this process, if at all.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var(WATCHDOG_PID_ENV) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    env::var(WATCHDOG_USEC_ENV)
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+}
+
+/// Splits a notification into its `VARIABLE=value` assignments.
+pub fn parse(datagram: &str) -> Vec<(&str, &str)> {
+    datagram
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect()
+}
+
+/// A notification socket that receives notifications from workers
+/// and tells them apart by the PID of the sender.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Listener {
+    socket: smol::Async<UnixDatagram>,
+    address: String,
+}
+
+#[cfg(target_os = "linux")]
+impl Listener {
+    /// Binds a new notification socket in the abstract socket
+    /// namespace.
+    pub fn bind() -> Result<Listener> {
+        use nix::sys::socket::{setsockopt, sockopt::PassCred};
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::{io::AsRawFd, net::SocketAddr};
+
+        let name = format!("kleinhirn/notify/{}", uuid::Uuid::new_v4());
+        let addr = SocketAddr::from_abstract_name(&name)?;
+        let socket = UnixDatagram::bind_addr(&addr)
+            .with_context(|| format!("Could not bind notification socket @{}", name))?;
+        setsockopt(socket.as_raw_fd(), PassCred, &true)
+            .context("Could not enable credential passing on the notification socket")?;
+        Ok(Listener {
+            socket: smol::Async::new(socket)?,
+            address: format!("@{}", name),
+        })
+    }
+
+    /// Returns the address to pass in `$NOTIFY_SOCKET`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Receives the next notification, along with the PID of the
+    /// process that sent it.
+    pub async fn receive(&self) -> Result<(u32, String)> {
+        use crate::worker_ack::receive_with_credentials;
+        use std::os::unix::io::AsRawFd;
+
+        let mut buf = vec![0u8; 4096];
+        let (len, pid) = self
+            .socket
+            .read_with(|socket| receive_with_credentials(socket.as_raw_fd(), &mut buf))
+            .await
+            .context("Could not receive a notification")?;
+        let pid = pid.context("Notification came without sender credentials")?;
+        Ok((pid, String::from_utf8_lossy(&buf[..len]).to_string()))
+    }
+}
@@ -0,0 +1,121 @@
+//! Passing listen sockets from systemd-style socket activation on to
+//! workers.
+//!
+//! Tools like `systemd` or `systemfd` pass listen sockets to the
+//! supervisor as FDs starting at 3, and describe them with the
+//! `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` environment
+//! variables. Since `LISTEN_PID` names the supervisor, programs using
+//! `sd_listen_fds` would refuse to use those sockets in a worker; so
+//! every worker gets `LISTEN_PID` rewritten to its own PID.
+
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use slog_scope::{debug, info};
+use std::{env, ffi::OsStr, process::Command};
+
+/// The first FD passed by socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+pub(crate) const LISTEN_PID_ENV: &str = "LISTEN_PID";
+pub(crate) const LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+pub(crate) const LISTEN_FDNAMES_ENV: &str = "LISTEN_FDNAMES";
+
+/// Sets `LISTEN_PID` to the PID of the shell, which `exec` then hands
+/// on to the program given in the remaining arguments.
+const SET_LISTEN_PID: &str = r#"LISTEN_PID=$$; export LISTEN_PID; exec "$@""#;
+
+/// The listen sockets that were passed to the supervisor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenFds {
+    count: usize,
+    names: Option<String>,
+}
+
+impl ListenFds {
+    /// Returns the listen sockets passed to this process, if any, and
+    /// makes sure they stay open in workers.
+    pub(crate) fn inherited() -> Result<Option<ListenFds>> {
+        let listen_fds = match ListenFds::from_vars(
+            std::process::id(),
+            env::var(LISTEN_PID_ENV).ok().as_deref(),
+            env::var(LISTEN_FDS_ENV).ok().as_deref(),
+            env::var(LISTEN_FDNAMES_ENV).ok().as_deref(),
+        )? {
+            Some(listen_fds) => listen_fds,
+            None => return Ok(None),
+        };
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + listen_fds.count as i32 {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                .with_context(|| format!("Could not keep listen socket FD {} open", fd))?;
+        }
+        info!("passing on listen sockets";
+              "count" => listen_fds.count, "names" => ?listen_fds.names);
+        Ok(Some(listen_fds))
+    }
+
+    /// Parses the values of the `LISTEN_PID`, `LISTEN_FDS` and
+    /// `LISTEN_FDNAMES` variables. Sockets meant for a process other
+    /// than `own_pid` are ignored.
+    pub fn from_vars(
+        own_pid: u32,
+        listen_pid: Option<&str>,
+        fds: Option<&str>,
+        names: Option<&str>,
+    ) -> Result<Option<ListenFds>> {
+        let pid = match listen_pid {
+            Some(pid) => pid,
+            None => return Ok(None),
+        };
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            debug!("ignoring listen sockets meant for another process"; "listen_pid" => pid);
+            return Ok(None);
+        }
+        let count: usize = fds
+            .context("LISTEN_PID is set, but LISTEN_FDS is not")?
+            .parse()
+            .context("Parsing LISTEN_FDS")?;
+        if let Some(names) = names {
+            let name_count = match names {
+                "" => 0,
+                names => names.split(':').count(),
+            };
+            if name_count != count {
+                bail!(
+                    "LISTEN_FDNAMES has {} names, but LISTEN_FDS is {}",
+                    name_count,
+                    count
+                );
+            }
+        }
+        Ok(Some(ListenFds {
+            count,
+            names: names.map(str::to_string),
+        }))
+    }
+
+    /// Returns the number of listen sockets.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the colon-separated names of the listen sockets, if
+    /// they have names.
+    pub fn names(&self) -> Option<&str> {
+        self.names.as_deref()
+    }
+
+    /// Returns a command that runs `argv` with the listen socket
+    /// variables set up for the worker process.
+    pub(crate) fn command<S: AsRef<OsStr>>(&self, shell: &str, argv: &[S]) -> Command {
+        let mut cmd = Command::new(shell);
+        cmd.arg("-c")
+            .arg(SET_LISTEN_PID)
+            .arg(shell)
+            .args(argv)
+            .env(LISTEN_FDS_ENV, self.count.to_string());
+        if let Some(names) = &self.names {
+            cmd.env(LISTEN_FDNAMES_ENV, names);
+        }
+        cmd
+    }
+}
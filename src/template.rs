@@ -0,0 +1,133 @@
+//! Variable substitution in the strings that configure a worker
+//! program.
+//!
+//! References to variables take the form `${variable}`; a literal
+//! `$` is written as `$$`. The following variables are known:
+//!
+//! * `${worker_id}` - the ID assigned to the worker
+//! * `${worker_index}` - the worker's slot index, in `0..count` (or
+//!   past it for a replacement that starts while the worker it
+//!   replaces is still running)
+//! * `${name}` - the name of the supervised service
+//! * `${version}` - the configured version of the service
+//! * `${env:VAR}` - the value of the environment variable `VAR` in
+//!   the supervisor process
+//!
+//! `$` followed by anything else stays as it is, so `$HOME` reaches
+//! the program unchanged. In [shell
+//! mode](crate::configuration::Program::shell), the shell's own `$$`
+//! (its PID) has to be written as `$$$$`, and `${VAR}` as `$${VAR}`.
+//!
+//! The service's version itself can only refer to `${env:VAR}` and
+//! to `${file:PATH}`, the contents of the file at `PATH` (relative to
+//! the configuration file) without surrounding whitespace; see
+//! [`expand_version`].
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// A problem expanding a template.
+#[derive(Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("unknown variable ${{{0}}}")]
+    UnknownVariable(String),
+
+    #[error("environment variable {0} is not set")]
+    UnsetEnvironmentVariable(String),
+
+    #[error("no version is configured")]
+    NoVersion,
+
+    #[error("unterminated variable reference in {0:?}")]
+    Unterminated(String),
+
+    #[error("could not read {0:?}: {1}")]
+    UnreadableFile(PathBuf, String),
+}
+
+/// Returns `input` with all variable references replaced by the
+/// values that `lookup` returns for them.
+fn expand_with(
+    input: &str,
+    lookup: impl Fn(&str) -> Result<String, TemplateError>,
+) -> Result<String, TemplateError> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if rest.starts_with('$') {
+            output.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| TemplateError::Unterminated(input.to_string()))?;
+            output.push_str(&lookup(&rest[1..end])?);
+            rest = &rest[end + 1..];
+        } else {
+            output.push('$');
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn lookup_env(name: &str) -> Result<String, TemplateError> {
+    env::var(name).map_err(|_| TemplateError::UnsetEnvironmentVariable(name.to_string()))
+}
+
+/// Returns the configured version of the service, `input`, with
+/// references to environment variables and files replaced. Relative
+/// file paths are resolved against `base_dir`.
+pub fn expand_version(input: &str, base_dir: &Path) -> Result<String, TemplateError> {
+    expand_with(input, |variable| {
+        if let Some(name) = variable.strip_prefix("env:") {
+            lookup_env(name)
+        } else if let Some(path) = variable.strip_prefix("file:") {
+            let path = base_dir.join(path);
+            fs::read_to_string(&path)
+                .map(|contents| contents.trim().to_string())
+                .map_err(|e| TemplateError::UnreadableFile(path, e.to_string()))
+        } else {
+            Err(TemplateError::UnknownVariable(variable.to_string()))
+        }
+    })
+}
+
+/// The values available for substitution into a worker's
+/// configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variables<'a> {
+    pub worker_id: &'a str,
+    pub worker_index: usize,
+    pub name: &'a str,
+    pub version: Option<&'a str>,
+}
+
+impl<'a> Variables<'a> {
+    /// Returns `input` with all variable references replaced by
+    /// their values.
+    pub fn expand(&self, input: &str) -> Result<String, TemplateError> {
+        expand_with(input, |variable| self.lookup(variable))
+    }
+
+    fn lookup(&self, variable: &str) -> Result<String, TemplateError> {
+        if let Some(name) = variable.strip_prefix("env:") {
+            return lookup_env(name);
+        }
+        match variable {
+            "worker_id" => Ok(self.worker_id.to_string()),
+            "worker_index" => Ok(self.worker_index.to_string()),
+            "name" => Ok(self.name.to_string()),
+            "version" => self
+                .version
+                .map(str::to_string)
+                .ok_or(TemplateError::NoVersion),
+            var => Err(TemplateError::UnknownVariable(var.to_string())),
+        }
+    }
+}
@@ -2,14 +2,16 @@
 //! the kleinhirn control process.
 
 use anyhow::{Context, Result};
-use futures::io::{BufReader, BufWriter};
+use futures::io::AsyncWriteExt;
 use nix::fcntl::{fcntl, FcntlArg};
 use nix::unistd::close;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use smol::Async;
 use std::{
-    fmt,
-    os::unix::{io::AsRawFd, net::UnixStream},
+    fmt, io,
+    os::unix::{io::IntoRawFd, net::UnixStream},
+    sync::Arc,
+    time::Duration,
 };
 
 /// Contains the worker's end of the control channel it uses to send
@@ -47,26 +49,143 @@ impl Drop for WorkerControlFD {
     }
 }
 
-pub(crate) type ControlChannel = BufWriter<BufReader<Async<UnixStream>>>;
+/// Our end of a control channel. Lines that arrive on it can be
+/// attributed to the process that sent them, where the OS tells us
+/// (on Linux, via `SO_PASSCRED`).
+#[derive(Debug, Clone)]
+pub(crate) struct ControlChannel {
+    stream: Arc<Async<UnixStream>>,
+}
+
+impl ControlChannel {
+    /// Sends `line`, which must end in a newline, to the other end.
+    pub(crate) async fn send(&self, line: &[u8]) -> Result<()> {
+        let mut stream = &*self.stream;
+        stream
+            .write_all(line)
+            .await
+            .context("Failed to send control message")?;
+        stream
+            .flush()
+            .await
+            .context("Could not flush control channel")?;
+        Ok(())
+    }
+
+    /// Returns a reader for the lines that arrive on the channel.
+    pub(crate) fn lines(&self) -> ControlLines {
+        ControlLines {
+            stream: self.stream.clone(),
+            buffer: vec![],
+            sender: None,
+        }
+    }
+}
+
+/// Reads lines off a [`ControlChannel`].
+#[derive(Debug)]
+pub(crate) struct ControlLines {
+    stream: Arc<Async<UnixStream>>,
+    buffer: Vec<u8>,
+    sender: Option<u32>,
+}
+
+impl ControlLines {
+    /// Returns the next line (without the newline) and the PID of
+    /// the process that sent it, if known, or `None` once the other
+    /// end has closed the channel.
+    pub(crate) async fn next_line(&mut self) -> Result<Option<(String, Option<u32>)>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line[..end]).to_string();
+                return Ok(Some((line, self.sender)));
+            }
+            let mut chunk = [0u8; 4096];
+            // The kernel never merges data from different senders
+            // into one read, so each chunk has exactly one sender:
+            let (len, sender) = self
+                .stream
+                .read_with(|stream| receive(stream, &mut chunk))
+                .await
+                .context("Could not read from the control channel")?;
+            if len == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+            self.sender = sender;
+        }
+    }
+}
+
+/// Reads from `stream` without blocking, returning the number of
+/// bytes read and the PID of the process that sent them.
+#[cfg(target_os = "linux")]
+fn receive(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<u32>)> {
+    use std::os::unix::io::AsRawFd;
+    receive_with_credentials(stream.as_raw_fd(), buf)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receive(mut stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<u32>)> {
+    use std::io::Read;
+    Ok((stream.read(buf)?, None))
+}
+
+/// Receives a message on `fd`, which must have `SO_PASSCRED` set,
+/// without blocking. Returns the number of bytes received and the
+/// PID of the process that sent them.
+#[cfg(target_os = "linux")]
+pub(crate) fn receive_with_credentials(
+    fd: std::os::unix::io::RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, Option<u32>)> {
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixCredentials};
+    use nix::sys::uio::IoVec;
+
+    let mut cmsg = nix::cmsg_space!(UnixCredentials);
+    let msg = recvmsg(
+        fd,
+        &[IoVec::from_mut_slice(buf)],
+        Some(&mut cmsg),
+        MsgFlags::MSG_DONTWAIT,
+    )
+    .map_err(|e| match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::other(e),
+    })?;
+    let pid = msg.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmCredentials(creds) => Some(creds.pid() as u32),
+        _ => None,
+    });
+    Ok((msg.bytes, pid))
+}
 
 /// Opens a streaming UNIX domain socket pair that can be passed to a
 /// worker, and returns the FD number of the writable end, and the
 /// readable end of the pair.
-pub fn worker_status_stream() -> Result<(WorkerControlFD, ControlChannel)> {
+pub(crate) fn worker_status_stream() -> Result<(WorkerControlFD, ControlChannel)> {
     let (ours, theirs_with_cloexec) =
         UnixStream::pair().context("Could not initialize preloader unix socket pair")?;
-    let their_fd = fcntl(
-        theirs_with_cloexec.as_raw_fd(),
-        FcntlArg::F_DUPFD(theirs_with_cloexec.as_raw_fd()),
-    )
-    .context("Could not clear CLOEXEC from the status pipe")?;
+    let theirs_with_cloexec = theirs_with_cloexec.into_raw_fd();
+    let their_fd = fcntl(theirs_with_cloexec, FcntlArg::F_DUPFD(theirs_with_cloexec))
+        .context("Could not clear CLOEXEC from the status pipe")?;
 
-    close(theirs_with_cloexec.as_raw_fd()).context("closing the remote FD")?;
+    close(theirs_with_cloexec).context("closing the remote FD")?;
+    #[cfg(target_os = "linux")]
+    {
+        use nix::sys::socket::{setsockopt, sockopt::PassCred};
+        use std::os::unix::io::AsRawFd;
+        // SO_PEERCRED would only name us, since we made the pair;
+        // the credentials passed with each message name the sender.
+        setsockopt(ours.as_raw_fd(), PassCred, &true)
+            .context("Could not enable credential passing on the control channel")?;
+    }
     Ok((
         WorkerControlFD::new(their_fd),
-        BufWriter::new(BufReader::new(
-            Async::new(ours).context("Could not convert our FD to async")?,
-        )),
+        ControlChannel {
+            stream: Arc::new(Async::new(ours).context("Could not convert our FD to async")?),
+        },
     ))
 }
 
@@ -79,6 +198,115 @@ pub fn worker_status_stream() -> Result<(WorkerControlFD, ControlChannel)> {
 pub enum WorkerControlMessage {
     /// A worker process with the given ID has finished initializing
     /// and is now able to do work. The `id` field must correspond to
-    /// the worker ID string given to the worker.
-    Ack { id: String },
+    /// the worker ID string given to the worker, and `token` to the
+    /// ack token in `$KLEINHIRN_ACK_TOKEN`.
+    Ack {
+        id: String,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(flatten)]
+        details: AckDetails,
+    },
+
+    /// The worker needs more time to start up: Its ack deadline moves
+    /// to `by` (like `"30s"`) from now, up to the configured
+    /// `max_ack_timeout`.
+    ExtendTimeout {
+        id: String,
+        #[serde(with = "humantime_serde")]
+        by: Duration,
+    },
+
+    /// What the worker is busy with while it starts up. It shows up
+    /// as the worker's status.
+    Progress { id: String, message: String },
+
+    /// The worker can't take work right now (say, it lost its
+    /// database connection), and should be counted as not ready
+    /// until it sends `ready`.
+    Unready { id: String, reason: String },
+
+    /// The worker can take work again after being `unready`.
+    Ready { id: String },
+
+    /// The worker's answer to the [`WorkerRequest`] numbered
+    /// `request`.
+    Reply {
+        id: String,
+        request: u64,
+        #[serde(default)]
+        reply: serde_json::Value,
+    },
+}
+
+impl WorkerControlMessage {
+    /// Returns the ID of the worker that sent the message.
+    pub fn id(&self) -> &str {
+        use WorkerControlMessage::*;
+        match self {
+            Ack { id, .. }
+            | ExtendTimeout { id, .. }
+            | Progress { id, .. }
+            | Unready { id, .. }
+            | Ready { id }
+            | Reply { id, .. } => id,
+        }
+    }
+}
+
+/// A request from the supervisor to a worker, sent on the worker's
+/// control channel. The worker may answer it with a
+/// [`Reply`](WorkerControlMessage::Reply) naming the `request` number.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct WorkerRequest {
+    pub request: u64,
+    #[serde(flatten)]
+    pub op: WorkerOp,
+}
+
+/// What the supervisor asks a worker to do.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerOp {
+    /// Stop taking new work, finish the work in flight and exit.
+    Drain,
+
+    /// Reopen log files, say because they were rotated.
+    ReopenLogs,
+
+    /// Describe the worker's state, in a reply or in its logs.
+    DumpStatus,
+
+    /// A command that only the worker program knows the meaning of.
+    Custom { command: String },
+}
+
+impl WorkerOp {
+    /// Returns the op called `name`; names that aren't built in make
+    /// custom commands.
+    pub fn named(name: &str) -> WorkerOp {
+        match name {
+            "drain" => WorkerOp::Drain,
+            "reopen_logs" => WorkerOp::ReopenLogs,
+            "dump_status" => WorkerOp::DumpStatus,
+            command => WorkerOp::Custom {
+                command: command.to_string(),
+            },
+        }
+    }
+}
+
+/// What a worker can optionally report about itself when it acks.
+#[derive(PartialEq, Debug, Clone, Default, Deserialize)]
+pub struct AckDetails {
+    /// The version of the code that the worker actually loaded. If
+    /// it differs from the configured version, the launch counts as
+    /// failed.
+    #[serde(default)]
+    pub version: Option<String>,
+
+    /// Free-form information about the worker's build, for logging.
+    #[serde(default)]
+    pub build_info: Option<serde_json::Value>,
 }
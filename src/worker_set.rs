@@ -1,59 +1,185 @@
-use crate::configuration::WorkerConfig;
+use crate::configuration::{AckTimeoutPolicy, WorkerConfig};
 use machine::*;
 use nix::unistd::Pid;
-use slog_scope::warn;
-use std::{collections::HashMap, fmt, time::Instant};
+use slog_scope::{info, warn};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Todo {
     KillProcess(Pid),
-    LaunchProcess,
+
+    /// Launch a worker into the given slot index.
+    LaunchProcess(usize),
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
 struct Worker {
     id: String,
+
+    /// The worker's slot, usually in `0..count`. Replacement workers
+    /// reuse the slot of the worker they replace once its process is
+    /// gone, and move into it if they had to start elsewhere; see
+    /// [`State::free_slot`] and [`State::reclaim_slots`].
+    index: usize,
     pid: Option<Pid>,
     requested: Option<Instant>,
     launched: Option<Instant>,
     acked: Option<Instant>,
     killed: Option<Instant>,
+
+    /// The point in time after which the worker should be replaced.
+    expires: Option<Instant>,
+
+    /// Set when the worker has outlived its lifetime and is waiting
+    /// for a replacement to come up.
+    retiring: Option<Instant>,
+
+    /// Set when the worker failed to ack in time and needs to be
+    /// killed.
+    timed_out: Option<Instant>,
+
+    /// The time by which the worker has to ack, if it asked for more
+    /// time than the configured ack timeout.
+    ack_deadline: Option<Instant>,
+
+    /// Set when launching the worker failed. The entry is kept around
+    /// until it is time to retry the launch.
+    launch_failed: Option<Instant>,
+
+    /// The most recent status text that the worker reported.
+    status: Option<String>,
+
+    /// Set while the worker reports that it can't take work, to the
+    /// reason it gave.
+    unready: Option<String>,
+
+    /// The sender of an ack that arrived before the worker's launch
+    /// was reported. Preloaders report the launch from an intermediate
+    /// process, so the worker can beat them to it.
+    early_ack: Option<Pid>,
+}
+
+impl Worker {
+    /// Returns the name of the phase of its lifecycle that the worker
+    /// is in.
+    fn phase(&self) -> &'static str {
+        match (self.launched, self.acked) {
+            (_, Some(_)) => "acked",
+            (Some(_), None) => "launched",
+            (None, None) if self.launch_failed.is_some() => "failed",
+            (None, None) => "requested",
+        }
+    }
+
+    /// Returns whether the worker keeps its slot from getting reused.
+    fn holds_slot(&self) -> bool {
+        self.pid.is_some() || self.launch_failed.is_none()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
 struct Workers {
     by_pid: HashMap<Pid, String>,
     by_id: HashMap<String, Worker>,
+
+    /// Processes that were reported as launched after their worker
+    /// was given up on, and that need to be killed.
+    strays: Vec<Pid>,
 }
 
 impl Workers {
-    fn register_worker(&mut self, id: String) {
+    fn register_worker(&mut self, id: String, index: usize) {
         let w = Worker {
             id: id.to_string(),
+            index,
             requested: Some(Instant::now()),
             ..Default::default()
         };
         self.by_id.insert(id, w);
     }
 
-    fn launched(&mut self, id: String, pid: Pid) {
-        self.by_id.entry(id.to_string()).and_modify(|w| {
-            w.launched = Some(Instant::now());
-            w.pid = Some(pid);
+    /// Records the launch of the worker `id`. Returns false if there
+    /// is no such worker (say, because its launch timed out and was
+    /// forgotten); the process then gets killed.
+    fn launched(&mut self, id: String, pid: Pid) -> bool {
+        match self.by_id.get_mut(&id) {
+            Some(w) => {
+                w.launched = Some(Instant::now());
+                w.pid = Some(pid);
+                self.by_pid.insert(pid, id);
+                true
+            }
+            None => {
+                warn!("a worker that was given up on launched, killing it";
+                      "worker_id" => &id, "pid" => pid.as_raw());
+                self.strays.push(pid);
+                false
+            }
+        }
+    }
+
+    fn acked(&mut self, id: String, lifetime: Option<Duration>) {
+        let now = Instant::now();
+        self.by_id.entry(id).and_modify(|w| {
+            w.acked = Some(now);
+            w.expires = lifetime.map(|lifetime| now + lifetime);
         });
-        self.by_pid.insert(pid, id);
     }
 
-    fn acked(&mut self, id: String) {
+    /// Records a launch failure on the worker entry. Failures of
+    /// launches that didn't get far enough to be assigned an ID get
+    /// an entry of their own.
+    fn launch_failed(&mut self, id: Option<String>) {
+        let now = Instant::now();
+        let id = id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        self.by_id
+            .entry(id.to_string())
+            .or_insert_with(|| Worker {
+                id,
+                ..Default::default()
+            })
+            .launch_failed = Some(now);
+    }
+
+    /// Removes the entries of failed launches that happened before
+    /// `cutoff`, making room for new workers.
+    fn clear_launch_failures(&mut self, cutoff: Instant) {
+        let by_pid = &mut self.by_pid;
+        self.by_id.retain(|_, w| match (w.launch_failed, w.pid) {
+            (Some(failed), pid) if failed < cutoff => {
+                if let Some(pid) = pid {
+                    by_pid.remove(&pid);
+                }
+                false
+            }
+            _ => true,
+        });
+    }
+
+    fn status(&mut self, id: String, status: String) {
         self.by_id.entry(id).and_modify(|w| {
-            w.acked = Some(Instant::now());
+            w.status = Some(status);
         });
     }
 
+    fn killed(&mut self, pid: Pid) {
+        self.strays.retain(|&stray| stray != pid);
+        if let Some(id) = self.by_pid.get(&pid) {
+            self.by_id.entry(id.to_string()).and_modify(|w| {
+                w.killed = Some(Instant::now());
+            });
+        }
+    }
+
     #[must_use = "It's important to check that the thing that got reaped is a worker of ours"]
     fn delete_by_pid(&mut self, pid: Pid) -> Option<Worker> {
-        if let Some(id) = self.by_pid.get(&pid) {
-            self.by_id.remove(id)
+        if let Some(id) = self.by_pid.remove(&pid) {
+            self.by_id.remove(&id)
         } else {
             None
         }
@@ -62,52 +188,406 @@ impl Workers {
     fn all(&self) -> impl Iterator<Item = &Worker> {
         self.by_id.values()
     }
+
+    /// Returns the workers that are neither killed nor waiting to get
+    /// replaced.
+    fn live(&self) -> impl Iterator<Item = &Worker> {
+        self.all().filter(|w| {
+            w.killed.is_none()
+                && w.retiring.is_none()
+                && w.timed_out.is_none()
+                && w.launch_failed.is_none()
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct State {
     workers: Workers,
     config: WorkerConfig,
+
+    /// The number of workers that failed and were replaced since the
+    /// worker set was last fully acked.
+    failures: usize,
+
+    /// The number of worker launches that failed in a row.
+    launch_failures: usize,
+
+    /// The most recent launch failure, for status reporting.
+    last_launch_failure: Option<WorkerLaunchFailure>,
+
+    /// What the process control is loading before workers can be
+    /// launched, and since when.
+    booting: Option<(String, Instant)>,
+
+    /// The condition that made the worker set unable to work, if any.
+    miserable: Option<MiserableCondition>,
 }
 
 impl State {
-    /// Checks if any workers that aren't acked yet, whose acks have
+    /// Checks if any workers that aren't launched or acked yet have
     /// timed out by the tick.
-    fn tick(self, time: Instant, ok_state: fn(Self) -> WorkerSet) -> WorkerSet {
+    fn tick(mut self, time: Instant, ok_state: fn(Self) -> WorkerSet) -> WorkerSet {
+        if let Some(timeout) = self.config.launch_timeout {
+            let launch_timeouts: Vec<&Worker> = self
+                .workers
+                .live()
+                .filter(|w| match (w.requested, w.launched) {
+                    (Some(requested), None) => requested + timeout < time,
+                    _ => false,
+                })
+                .collect();
+            let failures: Vec<WorkerLaunchFailure> = launch_timeouts
+                .into_iter()
+                .map(|w| {
+                    warn!("timed out waiting for worker to launch";
+                          "worker_id" => &w.id, "worker_index" => w.index, "phase" => w.phase(), "timeout" => ?timeout);
+                    WorkerLaunchFailure::new(
+                        Some(w.id.to_string()),
+                        format!("timed out after {:?} in phase {}", timeout, w.phase()),
+                    )
+                })
+                .collect();
+            for failure in failures {
+                if !self.record_launch_failure(failure) {
+                    return WorkerSet::faulted(self);
+                }
+            }
+        }
+        if let Some(cutoff) = time.checked_sub(self.config.launch_retry_delay) {
+            self.workers.clear_launch_failures(cutoff);
+        }
         if let Some(timeout) = self.config.ack_timeout {
             let ack_timeouts: Vec<&Worker> = self
                 .workers
-                .all()
+                .live()
                 .filter(|w| {
                     if let Some(launched) = w.launched {
-                        w.acked.is_none() && launched + timeout < time
+                        w.acked.is_none() && w.ack_deadline.unwrap_or(launched + timeout) < time
                     } else {
                         false
                     }
                 })
                 .collect();
             if !ack_timeouts.is_empty() {
-                warn!("timed out waiting for an ack from workers"; "workers" => ?ack_timeouts);
-                return WorkerSet::faulted(self);
+                for w in ack_timeouts.iter() {
+                    warn!("timed out waiting for an ack from worker";
+                          "worker_id" => &w.id, "worker_index" => w.index, "pid" => ?w.pid, "phase" => w.phase(), "timeout" => ?timeout);
+                }
+                let ids: Vec<String> = ack_timeouts.iter().map(|w| w.id.to_string()).collect();
+                if let AckTimeoutPolicy::Fault = self.config.on_ack_timeout {
+                    return WorkerSet::faulted(self);
+                }
+                if !self.record_failures(ids.len()) {
+                    return WorkerSet::faulted(self);
+                }
+                for id in ids {
+                    self.workers.by_id.entry(id).and_modify(|w| {
+                        w.timed_out = Some(time);
+                    });
+                }
             }
         }
+        self.retire_expired(time);
         ok_state(self)
     }
 
+    /// Records the worker's launch, and returns the ack that it sent
+    /// before the launch was reported, if any.
+    fn launched(&mut self, id: String, pid: Pid) -> Option<WorkerAcked> {
+        if !self.workers.launched(id.to_string(), pid) {
+            return None;
+        }
+        self.launch_failures = 0;
+        self.workers
+            .by_id
+            .get_mut(&id)
+            .and_then(|w| w.early_ack.take())
+            .map(|sender| WorkerAcked::new(id).with_sender(sender))
+    }
+
+    /// Records a failed launch on the worker. Returns false if too
+    /// many launches have failed in a row.
+    fn record_launch_failure(&mut self, failure: WorkerLaunchFailure) -> bool {
+        self.workers.launch_failed(failure.id.clone());
+        self.launch_failures += 1;
+        warn!("worker failed to launch";
+              "worker_id" => ?failure.id,
+              "reason" => &failure.reason,
+              "consecutive_failures" => self.launch_failures);
+        self.last_launch_failure = Some(failure);
+        if self.launch_failures >= self.config.max_launch_failures {
+            warn!("too many consecutive launch failures, giving up";
+                  "failures" => self.launch_failures);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn handle_launch_failure(
+        mut self,
+        failure: WorkerLaunchFailure,
+        ok_state: fn(Self) -> WorkerSet,
+    ) -> WorkerSet {
+        // Workers that were already running when they failed (say,
+        // with the wrong version) count against the failure budget,
+        // since launching them resets the consecutive failures:
+        let was_launched = failure
+            .id
+            .as_ref()
+            .and_then(|id| self.workers.by_id.get(id))
+            .map(|w| w.pid.is_some())
+            .unwrap_or(false);
+        if self.record_launch_failure(failure) && (!was_launched || self.record_failures(1)) {
+            ok_state(self)
+        } else {
+            WorkerSet::faulted(self)
+        }
+    }
+
+    /// Counts failed workers against the failure budget. Returns
+    /// false if the budget is exhausted.
+    fn record_failures(&mut self, n: usize) -> bool {
+        self.failures += n;
+        if self.failures > self.config.failure_budget {
+            warn!("too many workers failed, giving up";
+                  "failures" => self.failures, "budget" => self.config.failure_budget);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Marks all workers that have outlived their lifetime as
+    /// retiring, which causes a replacement to be launched for each.
+    fn retire_expired(&mut self, time: Instant) {
+        for w in self.workers.by_id.values_mut() {
+            match (w.expires, w.retiring, w.killed) {
+                (Some(expires), None, None) if expires < time => {
+                    info!("worker reached its maximum lifetime, replacing it";
+                          "worker_id" => &w.id, "worker_index" => w.index, "pid" => ?w.pid);
+                    w.retiring = Some(time);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns true if enough workers are live and acked.
+    fn fully_acked(&self) -> bool {
+        self.workers.live().filter(|w| w.acked.is_some()).count() >= self.config.count
+    }
+
+    /// Returns false (and complains) if a message about the worker
+    /// `id` came from a process other than the worker itself.
+    fn sent_by_worker(&self, id: &str, sender: Option<Pid>, what: &str) -> bool {
+        match sender {
+            Some(sender) if self.workers.by_pid.get(&sender).map(String::as_str) != Some(id) => {
+                warn!("ignoring a message that was not sent by the worker process";
+                      "message" => what, "worker_id" => id, "sender_pid" => sender.as_raw());
+                false
+            }
+            _ => true,
+        }
+    }
+
     fn handle_ack<T>(
         mut self,
-        id: String,
+        ack: WorkerAcked,
         self_state: fn(Self) -> T,
         done_state: fn(Self) -> T,
     ) -> T {
-        self.workers.acked(id);
+        if let (Some(sender), Some(w)) = (ack.sender, self.workers.by_id.get_mut(&ack.id)) {
+            if w.pid.is_none() && w.launch_failed.is_none() {
+                info!("holding on to an ack that arrived before the worker's launch";
+                      "worker_id" => &w.id, "sender_pid" => sender.as_raw());
+                w.early_ack = Some(sender);
+                return self_state(self);
+            }
+        }
+        if !self.sent_by_worker(&ack.id, ack.sender, "ack") {
+            return self_state(self);
+        }
+        let lifetime = self.config.worker_lifetime();
+        self.workers.acked(ack.id, lifetime);
 
-        if self.workers.all().filter(|w| w.acked.is_some()).count() >= self.config.count {
+        if self.fully_acked() {
+            // The failed workers have all been replaced, so the budget
+            // is there for the next failures again:
+            if self.failures > 0 {
+                info!("all workers acked, resetting the failure count";
+                      "failures" => self.failures);
+                self.failures = 0;
+            }
             done_state(self)
         } else {
             self_state(self)
         }
     }
+
+    /// Moves the ack deadline of a worker that asked for more time to
+    /// `by` from now, but never earlier than the ack timeout and never
+    /// later than [`WorkerConfig.max_ack_timeout`] after it launched.
+    fn extend_ack_deadline(&mut self, ext: WorkerExtendTimeout) {
+        if !self.sent_by_worker(&ext.id, ext.sender, "extend_timeout") {
+            return;
+        }
+        let (timeout, ceiling) = match (self.config.ack_timeout, self.config.max_ack_timeout) {
+            (Some(timeout), Some(ceiling)) => (timeout, ceiling),
+            (None, _) => return,
+            (Some(_), None) => {
+                warn!("worker asked for more time to ack, but max_ack_timeout is not set";
+                      "worker_id" => &ext.id, "by" => ?ext.by);
+                return;
+            }
+        };
+        let now = Instant::now();
+        if let Some(w) = self.workers.by_id.get_mut(&ext.id) {
+            if let (Some(launched), None) = (w.launched, w.acked) {
+                let current = w.ack_deadline.unwrap_or(launched + timeout);
+                let deadline = current
+                    .max(now + ext.by)
+                    .min(launched + ceiling)
+                    .max(launched + timeout);
+                info!("worker asked for more time to ack";
+                      "worker_id" => &w.id, "worker_index" => w.index, "by" => ?ext.by,
+                      "remaining" => ?deadline.saturating_duration_since(now));
+                w.ack_deadline = Some(deadline);
+            }
+        }
+    }
+
+    /// Records whether the worker can take work right now.
+    fn readiness(&mut self, r: WorkerReadiness) {
+        if !self.sent_by_worker(&r.id, r.sender, "readiness") {
+            return;
+        }
+        if let Some(w) = self.workers.by_id.get_mut(&r.id) {
+            match (&w.unready, &r.unready) {
+                (_, Some(reason)) => warn!("worker is not ready";
+                                           "worker_id" => &w.id, "worker_index" => w.index, "reason" => reason),
+                (Some(_), None) => info!("worker is ready again";
+                                         "worker_id" => &w.id, "worker_index" => w.index),
+                (None, None) => {}
+            }
+            w.unready = r.unready;
+        }
+    }
+
+    /// Returns the lowest slot index that isn't taken by a live
+    /// worker or by a worker process that is still around, like a
+    /// retiring worker waiting for its replacement. Such replacements
+    /// get an index past `count`, so that two processes never share a
+    /// slot (and the port that might be derived from it).
+    fn free_slot(&self) -> Option<usize> {
+        (0..).find(|&index| {
+            !self
+                .workers
+                .all()
+                .any(|w| w.index == index && w.holds_slot())
+        })
+    }
+
+    /// Moves workers with a slot past `count` into the slots below
+    /// `count` that got freed up, so that their replacements launch
+    /// into `0..count` again. The worker processes themselves keep
+    /// the index they were launched with.
+    fn reclaim_slots(&mut self) {
+        let count = self.config.count;
+        while let Some(index) = self.free_slot().filter(|&index| index < count) {
+            match self
+                .workers
+                .by_id
+                .values_mut()
+                .filter(|w| w.index >= count && w.holds_slot())
+                .min_by_key(|w| w.index)
+            {
+                Some(w) => {
+                    info!("moving worker into a freed-up slot";
+                          "worker_id" => &w.id, "worker_index" => w.index, "slot" => index);
+                    w.index = index;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Forgets the worker whose process `pid` is gone, and lets the
+    /// workers past `count` take over its slot.
+    #[must_use = "It's important to check that the thing that got reaped is a worker of ours"]
+    fn delete_by_pid(&mut self, pid: Pid) -> Option<Worker> {
+        let deleted = self.workers.delete_by_pid(pid);
+        if deleted.is_some() {
+            self.reclaim_slots();
+        }
+        deleted
+    }
+
+    /// Resets the failure counters and forgets about failed launches,
+    /// keeping track of the workers that are still around. There is no
+    /// recovering from a dead preloader, since it is what launches
+    /// the workers.
+    fn recover(mut self) -> WorkerSet {
+        if let Some(MiserableCondition::PreloaderDied) = self.miserable {
+            warn!("the preloader is dead, so the worker set can't recover; restart the supervisor");
+            return WorkerSet::faulted(self);
+        }
+        self.failures = 0;
+        self.launch_failures = 0;
+        let by_pid = &mut self.workers.by_pid;
+        self.workers
+            .by_id
+            .retain(|_, w| match (w.launch_failed, w.pid) {
+                (Some(_), pid) => {
+                    if let Some(pid) = pid {
+                        by_pid.remove(&pid);
+                    }
+                    false
+                }
+                (None, _) => true,
+            });
+        if self.fully_acked() {
+            WorkerSet::running(self)
+        } else if self.workers.live().any(|w| w.acked.is_some()) {
+            WorkerSet::underprovisioned(self)
+        } else {
+            WorkerSet::startup(self)
+        }
+    }
+
+    /// Kills stray processes and workers that timed out or failed to
+    /// launch, launches workers until the configured count is live
+    /// (unless a failed launch is waiting to be retried), and kills
+    /// retiring workers once their replacements have acked.
+    fn required_action(&self) -> Option<Todo> {
+        if let Some(&pid) = self.workers.strays.first() {
+            return Some(Todo::KillProcess(pid));
+        }
+        if let Some(pid) = self
+            .workers
+            .all()
+            .filter(|w| (w.timed_out.is_some() || w.launch_failed.is_some()) && w.killed.is_none())
+            .find_map(|w| w.pid)
+        {
+            return Some(Todo::KillProcess(pid));
+        }
+        if self.workers.live().count() < self.config.count {
+            if self.workers.all().any(|w| w.launch_failed.is_some()) {
+                return None;
+            }
+            return self.free_slot().map(Todo::LaunchProcess);
+        }
+        if self.fully_acked() {
+            return self
+                .workers
+                .all()
+                .filter(|w| w.retiring.is_some() && w.killed.is_none())
+                .find_map(|w| w.pid)
+                .map(Todo::KillProcess);
+        }
+        None
+    }
 }
 
 machine! {
@@ -147,29 +627,53 @@ impl fmt::Debug for WorkerSet {
                 return Ok(());
             }
         };
+        let count_phase = |phase| state.workers.all().filter(|w| w.phase() == phase).count();
         write!(
             f,
             "(acked:{}, launched:{}, requested:{})/{}",
-            state.workers.all().filter(|w| w.acked.is_some()).count(),
-            state
-                .workers
-                .all()
-                .filter(|w| w.acked.is_none() && w.launched.is_some())
-                .count(),
-            state
-                .workers
-                .all()
-                .filter(|w| w.acked.is_none() && w.launched.is_none() && w.requested.is_some())
-                .count(),
+            count_phase("acked"),
+            count_phase("launched"),
+            count_phase("requested"),
             state.config.count,
         )?;
+        let mut workers: Vec<&Worker> = state.workers.all().collect();
+        workers.sort_by_key(|w| w.index);
+        let slots: Vec<String> = workers
+            .iter()
+            .map(|w| {
+                let mut slot = match &w.status {
+                    Some(status) => format!("{}:{}({:?})", w.index, w.phase(), status),
+                    None => format!("{}:{}", w.index, w.phase()),
+                };
+                if let Some(reason) = &w.unready {
+                    slot.push_str(&format!(":unready({:?})", reason));
+                }
+                slot
+            })
+            .collect();
+        write!(f, " [{}]", slots.join(" "))?;
+        if let Some((step, since)) = &state.booting {
+            write!(
+                f,
+                " booting: loading {:?} for {}s",
+                step,
+                since.elapsed().as_secs()
+            )?;
+        }
+        if let Some(failure) = &state.last_launch_failure {
+            write!(
+                f,
+                " launch failures:{} (last: {:?}: {})",
+                state.launch_failures, failure.id, failure.reason
+            )?;
+        }
         Ok(())
     }
 }
 
 methods!(WorkerSet, [
     // TODO: Faulted?
-    Startup, Underprovisioned => fn required_action(&self) -> Option<Todo>,
+    Startup, Running, Underprovisioned => fn required_action(&self) -> Option<Todo>,
     Startup, Running, Underprovisioned => fn working(&self) -> bool
 ]);
 
@@ -182,14 +686,25 @@ impl WorkerDeath {
     }
 }
 
+/// The supervisor has sent a termination signal to a worker process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerKilled(pub Pid);
+
+impl WorkerKilled {
+    pub fn new(pid: Pid) -> Self {
+        WorkerKilled(pid)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct WorkerRequested {
     id: String,
+    index: usize,
 }
 
 impl WorkerRequested {
-    pub fn new(id: String) -> Self {
-        Self { id }
+    pub fn new(id: String, index: usize) -> Self {
+        Self { id, index }
     }
 }
 
@@ -208,34 +723,147 @@ impl WorkerLaunched {
 #[derive(Clone, Debug, PartialEq)]
 pub struct WorkerAcked {
     id: String,
+    sender: Option<Pid>,
 }
 
 impl WorkerAcked {
     pub fn new(id: String) -> Self {
-        Self { id }
+        Self { id, sender: None }
+    }
+
+    /// Records the PID of the process that sent the ack, if the
+    /// channel it came in on tells. Acks that weren't sent by the
+    /// worker process itself get ignored.
+    pub fn with_sender(self, sender: Pid) -> Self {
+        Self {
+            sender: Some(sender),
+            ..self
+        }
+    }
+}
+
+/// A worker that hasn't acked yet asked for its ack deadline to be
+/// moved to `by` from now.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerExtendTimeout {
+    id: String,
+    by: Duration,
+    sender: Option<Pid>,
+}
+
+impl WorkerExtendTimeout {
+    pub fn new(id: String, by: Duration) -> Self {
+        Self {
+            id,
+            by,
+            sender: None,
+        }
+    }
+
+    /// Records the PID of the process that sent the request; see
+    /// [`WorkerAcked::with_sender`].
+    pub fn with_sender(self, sender: Pid) -> Self {
+        Self {
+            sender: Some(sender),
+            ..self
+        }
+    }
+}
+
+/// A worker has reported that it can't take work for now, and why,
+/// or that it can take work again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerReadiness {
+    id: String,
+    unready: Option<String>,
+    sender: Option<Pid>,
+}
+
+impl WorkerReadiness {
+    pub fn unready(id: String, reason: String) -> Self {
+        Self {
+            id,
+            unready: Some(reason),
+            sender: None,
+        }
+    }
+
+    pub fn ready(id: String) -> Self {
+        Self {
+            id,
+            unready: None,
+            sender: None,
+        }
+    }
+
+    /// Records the PID of the process that sent the report; see
+    /// [`WorkerAcked::with_sender`].
+    pub fn with_sender(self, sender: Pid) -> Self {
+        Self {
+            sender: Some(sender),
+            ..self
+        }
+    }
+}
+
+/// A worker has reported a human-readable status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerStatus {
+    id: String,
+    status: String,
+}
+
+impl WorkerStatus {
+    pub fn new(id: String, status: String) -> Self {
+        Self { id, status }
+    }
+}
+
+/// The process control has started loading a file (or is done
+/// loading) before workers can be launched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootProgress {
+    step: Option<(String, Instant)>,
+}
+
+impl BootProgress {
+    pub fn loading(step: String, since: Instant) -> Self {
+        Self {
+            step: Some((step, since)),
+        }
+    }
+
+    pub fn done() -> Self {
+        Self { step: None }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct WorkerLaunchFailure {
     id: Option<String>,
+    reason: String,
 }
 
 impl WorkerLaunchFailure {
-    pub fn new(id: Option<String>) -> Self {
-        Self { id }
+    pub fn new(id: Option<String>, reason: String) -> Self {
+        Self { id, reason }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Terminate();
 
+/// The cooldown after a fault has passed, and the worker set should
+/// try to get going again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recover;
+
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub enum MiserableCondition {
     PreloaderDied,
 }
 
-/// A timer tick (typically half the ack timeout) has come in.
+/// A timer tick (typically half the shortest configured timeout) has come in.
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub struct Tick(Instant);
 
@@ -247,37 +875,109 @@ impl Tick {
 
 transitions!(WorkerSet, [
     (Startup, WorkerRequested) => Startup,
-    (Startup, WorkerLaunched) => Startup,
+    (Startup, WorkerLaunched) => [Startup, Running],
     (Startup, WorkerAcked) => [Running, Startup],
     (Startup, Tick) => [Startup, Faulted],
-    (Startup, WorkerLaunchFailure) => Faulted,
+    (Startup, WorkerLaunchFailure) => [Startup, Faulted],
     (Startup, WorkerDeath) => [Startup, Faulted],
+    (Startup, WorkerKilled) => Startup,
+    (Startup, WorkerStatus) => Startup,
+    (Startup, WorkerExtendTimeout) => Startup,
+    (Startup, WorkerReadiness) => Startup,
+    (Startup, BootProgress) => Startup,
     (Startup, MiserableCondition) => Faulted,
 
+    (Running, WorkerRequested) => Running,
+    (Running, WorkerLaunched) => Running,
     (Running, WorkerDeath) => [Running, Underprovisioned],
     (Running, WorkerAcked) => Running,
     (Running, Tick) => [Running, Faulted],
+    (Running, WorkerLaunchFailure) => [Running, Faulted],
+    (Running, WorkerKilled) => Running,
+    (Running, WorkerStatus) => Running,
+    (Running, WorkerExtendTimeout) => Running,
+    (Running, WorkerReadiness) => Running,
     (Running, MiserableCondition) => Faulted,
 
     (Underprovisioned, WorkerRequested) => Underprovisioned,
-    (Underprovisioned, WorkerLaunched) => Underprovisioned,
+    (Underprovisioned, WorkerLaunched) => [Underprovisioned, Running],
     (Underprovisioned, WorkerAcked) => [Running, Underprovisioned],
     (Underprovisioned, Tick) => [Underprovisioned, Faulted],
-    (Underprovisioned, WorkerLaunchFailure) => Faulted,
+    (Underprovisioned, WorkerLaunchFailure) => [Underprovisioned, Faulted],
     (Underprovisioned, WorkerDeath) => [Underprovisioned, Faulted],
-    (Underprovisioned, MiserableCondition) => Faulted
+    (Underprovisioned, WorkerKilled) => Underprovisioned,
+    (Underprovisioned, WorkerStatus) => Underprovisioned,
+    (Underprovisioned, WorkerExtendTimeout) => Underprovisioned,
+    (Underprovisioned, WorkerReadiness) => Underprovisioned,
+    (Underprovisioned, MiserableCondition) => Faulted,
+
+    (Faulted, WorkerDeath) => Faulted,
+    (Faulted, WorkerStatus) => Faulted,
+    (Faulted, WorkerReadiness) => Faulted,
+    (Faulted, MiserableCondition) => Faulted,
+    (Faulted, Recover) => [Startup, Running, Underprovisioned, Faulted]
 ]);
 
 impl Running {
+    fn on_worker_requested(self, r: WorkerRequested) -> Running {
+        let mut state = self.state;
+        state.workers.register_worker(r.id, r.index);
+
+        Running { state }
+    }
+
+    fn on_worker_launched(self, r: WorkerLaunched) -> Running {
+        let mut state = self.state;
+        match state.launched(r.id, r.pid) {
+            Some(ack) => {
+                state.handle_ack(ack, |state| Running { state }, |state| Running { state })
+            }
+            None => Running { state },
+        }
+    }
+
     fn on_worker_death(self, d: WorkerDeath) -> WorkerSet {
         let mut state = self.state;
-        if state.workers.delete_by_pid(d.0).is_some() {
+        if state.delete_by_pid(d.0).is_some() && !state.fully_acked() {
             WorkerSet::underprovisioned(state)
         } else {
             WorkerSet::running(state)
         }
     }
 
+    fn on_worker_killed(self, k: WorkerKilled) -> Running {
+        let mut state = self.state;
+        state.workers.killed(k.0);
+
+        Running { state }
+    }
+
+    fn on_worker_status(self, s: WorkerStatus) -> Running {
+        let mut state = self.state;
+        state.workers.status(s.id, s.status);
+
+        Running { state }
+    }
+
+    fn on_worker_readiness(self, r: WorkerReadiness) -> Running {
+        let mut state = self.state;
+        state.readiness(r);
+
+        Running { state }
+    }
+
+    fn on_worker_extend_timeout(self, e: WorkerExtendTimeout) -> Running {
+        let mut state = self.state;
+        state.extend_ack_deadline(e);
+
+        Running { state }
+    }
+
+    fn on_worker_launch_failure(self, t: WorkerLaunchFailure) -> WorkerSet {
+        let state = self.state;
+        state.handle_launch_failure(t, WorkerSet::running)
+    }
+
     fn on_tick(self, s: Tick) -> WorkerSet {
         let state = self.state;
         state.tick(s.0, WorkerSet::running)
@@ -285,14 +985,19 @@ impl Running {
 
     fn on_worker_acked(self, s: WorkerAcked) -> Running {
         let state = self.state;
-        state.handle_ack(s.id, |state| Running { state }, |state| Running { state })
+        state.handle_ack(s, |state| Running { state }, |state| Running { state })
     }
 
-    fn on_miserable_condition(self, _s: MiserableCondition) -> Faulted {
-        let state = self.state;
+    fn on_miserable_condition(self, s: MiserableCondition) -> Faulted {
+        let mut state = self.state;
+        state.miserable = Some(s);
         Faulted { state }
     }
 
+    fn required_action(&self) -> Option<Todo> {
+        self.state.required_action()
+    }
+
     fn working(&self) -> bool {
         true
     }
@@ -301,16 +1006,17 @@ impl Running {
 impl Startup {
     fn on_worker_requested(self, r: WorkerRequested) -> Startup {
         let mut state = self.state;
-        state.workers.register_worker(r.id);
+        state.workers.register_worker(r.id, r.index);
 
         Startup { state }
     }
 
-    fn on_worker_launched(self, r: WorkerLaunched) -> Startup {
+    fn on_worker_launched(self, r: WorkerLaunched) -> WorkerSet {
         let mut state = self.state;
-        state.workers.launched(r.id, r.pid);
-
-        Startup { state }
+        match state.launched(r.id, r.pid) {
+            Some(ack) => state.handle_ack(ack, WorkerSet::startup, WorkerSet::running),
+            None => WorkerSet::startup(state),
+        }
     }
 
     fn on_tick(self, s: Tick) -> WorkerSet {
@@ -320,41 +1026,65 @@ impl Startup {
 
     fn on_worker_acked(self, s: WorkerAcked) -> WorkerSet {
         let state = self.state;
-        state.handle_ack(s.id, WorkerSet::startup, WorkerSet::running)
+        state.handle_ack(s, WorkerSet::startup, WorkerSet::running)
     }
 
-    fn on_worker_launch_failure(self, _t: WorkerLaunchFailure) -> Faulted {
-        // TODO: mark worker as broken
-        Faulted { state: self.state }
+    fn on_worker_launch_failure(self, t: WorkerLaunchFailure) -> WorkerSet {
+        let state = self.state;
+        state.handle_launch_failure(t, WorkerSet::startup)
     }
 
     fn on_worker_death(self, d: WorkerDeath) -> WorkerSet {
         let mut state = self.state;
-        if state.workers.delete_by_pid(d.0).is_some() {
-            WorkerSet::faulted(state)
-        } else {
-            WorkerSet::startup(state)
+        match state.delete_by_pid(d.0) {
+            Some(w) if w.killed.is_none() => WorkerSet::faulted(state),
+            _ => WorkerSet::startup(state),
         }
     }
 
-    fn on_miserable_condition(self, _s: MiserableCondition) -> Faulted {
-        let state = self.state;
+    fn on_worker_killed(self, k: WorkerKilled) -> Startup {
+        let mut state = self.state;
+        state.workers.killed(k.0);
+
+        Startup { state }
+    }
+
+    fn on_worker_status(self, s: WorkerStatus) -> Startup {
+        let mut state = self.state;
+        state.workers.status(s.id, s.status);
+
+        Startup { state }
+    }
+
+    fn on_worker_readiness(self, r: WorkerReadiness) -> Startup {
+        let mut state = self.state;
+        state.readiness(r);
+
+        Startup { state }
+    }
+
+    fn on_worker_extend_timeout(self, e: WorkerExtendTimeout) -> Startup {
+        let mut state = self.state;
+        state.extend_ack_deadline(e);
+
+        Startup { state }
+    }
+
+    fn on_boot_progress(self, p: BootProgress) -> Startup {
+        let mut state = self.state;
+        state.booting = p.step;
+
+        Startup { state }
+    }
+
+    fn on_miserable_condition(self, s: MiserableCondition) -> Faulted {
+        let mut state = self.state;
+        state.miserable = Some(s);
         Faulted { state }
     }
 
     fn required_action(&self) -> Option<Todo> {
-        if self
-            .state
-            .workers
-            .all()
-            .filter(|w| w.killed.is_none())
-            .count()
-            < self.state.config.count
-        {
-            Some(Todo::LaunchProcess)
-        } else {
-            None
-        }
+        self.state.required_action()
     }
 
     fn working(&self) -> bool {
@@ -365,16 +1095,17 @@ impl Startup {
 impl Underprovisioned {
     fn on_worker_requested(self, r: WorkerRequested) -> Underprovisioned {
         let mut state = self.state;
-        state.workers.register_worker(r.id);
+        state.workers.register_worker(r.id, r.index);
 
         Underprovisioned { state }
     }
 
-    fn on_worker_launched(self, r: WorkerLaunched) -> Underprovisioned {
+    fn on_worker_launched(self, r: WorkerLaunched) -> WorkerSet {
         let mut state = self.state;
-        state.workers.launched(r.id, r.pid);
-
-        Underprovisioned { state }
+        match state.launched(r.id, r.pid) {
+            Some(ack) => state.handle_ack(ack, WorkerSet::underprovisioned, WorkerSet::running),
+            None => WorkerSet::underprovisioned(state),
+        }
     }
 
     fn on_tick(self, s: Tick) -> WorkerSet {
@@ -384,40 +1115,58 @@ impl Underprovisioned {
 
     fn on_worker_acked(self, s: WorkerAcked) -> WorkerSet {
         let state = self.state;
-        state.handle_ack(s.id, WorkerSet::underprovisioned, WorkerSet::running)
+        state.handle_ack(s, WorkerSet::underprovisioned, WorkerSet::running)
     }
 
-    fn on_worker_launch_failure(self, _t: WorkerLaunchFailure) -> Faulted {
-        // TODO: mark worker as broken
-        Faulted { state: self.state }
+    fn on_worker_launch_failure(self, t: WorkerLaunchFailure) -> WorkerSet {
+        let state = self.state;
+        state.handle_launch_failure(t, WorkerSet::underprovisioned)
     }
 
     fn on_worker_death(self, d: WorkerDeath) -> WorkerSet {
         let mut state = self.state;
-        if state.workers.delete_by_pid(d.0).is_some() {
+        if state.delete_by_pid(d.0).is_some() {
             // TODO: treat this better with a circuit breaker (figure out what we want in the first place?)
         }
         WorkerSet::underprovisioned(state)
     }
 
-    fn on_miserable_condition(self, _s: MiserableCondition) -> Faulted {
-        let state = self.state;
+    fn on_worker_killed(self, k: WorkerKilled) -> Underprovisioned {
+        let mut state = self.state;
+        state.workers.killed(k.0);
+
+        Underprovisioned { state }
+    }
+
+    fn on_worker_status(self, s: WorkerStatus) -> Underprovisioned {
+        let mut state = self.state;
+        state.workers.status(s.id, s.status);
+
+        Underprovisioned { state }
+    }
+
+    fn on_worker_readiness(self, r: WorkerReadiness) -> Underprovisioned {
+        let mut state = self.state;
+        state.readiness(r);
+
+        Underprovisioned { state }
+    }
+
+    fn on_worker_extend_timeout(self, e: WorkerExtendTimeout) -> Underprovisioned {
+        let mut state = self.state;
+        state.extend_ack_deadline(e);
+
+        Underprovisioned { state }
+    }
+
+    fn on_miserable_condition(self, s: MiserableCondition) -> Faulted {
+        let mut state = self.state;
+        state.miserable = Some(s);
         Faulted { state }
     }
 
     fn required_action(&self) -> Option<Todo> {
-        if self
-            .state
-            .workers
-            .all()
-            .filter(|w| w.killed.is_none())
-            .count()
-            < self.state.config.count
-        {
-            Some(Todo::LaunchProcess)
-        } else {
-            None
-        }
+        self.state.required_action()
     }
 
     fn working(&self) -> bool {
@@ -425,12 +1174,126 @@ impl Underprovisioned {
     }
 }
 
+impl Faulted {
+    fn on_worker_death(self, d: WorkerDeath) -> Faulted {
+        let mut state = self.state;
+        // Keep track of the dead, in case we recover:
+        let _ = state.delete_by_pid(d.0);
+        Faulted { state }
+    }
+
+    fn on_worker_status(self, s: WorkerStatus) -> Faulted {
+        let mut state = self.state;
+        state.workers.status(s.id, s.status);
+
+        Faulted { state }
+    }
+
+    fn on_worker_readiness(self, r: WorkerReadiness) -> Faulted {
+        let mut state = self.state;
+        state.readiness(r);
+
+        Faulted { state }
+    }
+
+    fn on_miserable_condition(self, s: MiserableCondition) -> Faulted {
+        let mut state = self.state;
+        state.miserable = Some(s);
+        Faulted { state }
+    }
+
+    fn on_recover(self, _r: Recover) -> WorkerSet {
+        let state = self.state;
+        state.recover()
+    }
+}
+
 impl WorkerSet {
     pub fn new(config: WorkerConfig) -> WorkerSet {
         let state = State {
             config,
             workers: Default::default(),
+            failures: 0,
+            launch_failures: 0,
+            last_launch_failure: None,
+            booting: None,
+            miserable: None,
         };
         WorkerSet::Startup(Startup { state })
     }
+
+    /// Returns what the worker set is loading while it starts up, and
+    /// since when.
+    pub fn booting(&self) -> Option<(&str, Instant)> {
+        match self {
+            WorkerSet::Startup(Startup { state }) => state
+                .booting
+                .as_ref()
+                .map(|(step, since)| (step.as_str(), *since)),
+            _ => None,
+        }
+    }
+
+    fn state(&self) -> Option<&State> {
+        match self {
+            WorkerSet::Startup(Startup { state })
+            | WorkerSet::Running(Running { state })
+            | WorkerSet::Underprovisioned(Underprovisioned { state })
+            | WorkerSet::Faulted(Faulted { state }) => Some(state),
+            WorkerSet::Error => None,
+        }
+    }
+
+    /// Returns the slot index and reason of each live worker that
+    /// has acked, but reported that it can't take work right now.
+    pub fn unready(&self) -> Vec<(usize, &str)> {
+        let state = match self.state() {
+            Some(state) => state,
+            None => return vec![],
+        };
+        let mut unready: Vec<(usize, &str)> = state
+            .workers
+            .live()
+            .filter(|w| w.acked.is_some())
+            .filter_map(|w| w.unready.as_deref().map(|reason| (w.index, reason)))
+            .collect();
+        unready.sort_unstable();
+        unready
+    }
+
+    /// Returns the IDs of the live workers that have acked.
+    pub fn acked_workers(&self) -> Vec<String> {
+        self.state()
+            .map(|state| {
+                state
+                    .workers
+                    .live()
+                    .filter(|w| w.acked.is_some())
+                    .map(|w| w.id.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the ID of the worker with the PID `pid`, if it hasn't
+    /// been reaped yet.
+    pub fn worker_id(&self, pid: Pid) -> Option<String> {
+        self.state()
+            .and_then(|state| state.workers.by_pid.get(&pid))
+            .cloned()
+    }
+
+    /// Returns the PID of the worker `id`, if it has launched and
+    /// hasn't been reaped yet.
+    pub fn worker_pid(&self, id: &str) -> Option<Pid> {
+        self.state()
+            .and_then(|state| state.workers.by_id.get(id))
+            .and_then(|w| w.pid)
+    }
+
+    /// Returns how long workers get to drain before they're sent
+    /// SIGTERM. See [`WorkerConfig.drain_timeout`].
+    pub fn drain_timeout(&self) -> Option<Duration> {
+        self.state().and_then(|state| state.config.drain_timeout)
+    }
 }
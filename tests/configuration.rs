@@ -0,0 +1,281 @@
+use kleinhirn::configuration::{AckStrategy, Config, WorkerKind};
+#[cfg(target_os = "linux")]
+use kleinhirn::configuration::{Ruby, SharedPreloader};
+use kleinhirn::worker_ack::WorkerOp;
+use nix::sys::signal::Signal;
+use std::time::Duration;
+
+fn parse(toml: &str) -> Config {
+    let mut settings = config::Config::default();
+    settings
+        .merge(config::File::from_str(toml, config::FileFormat::Toml))
+        .expect("merging config");
+    settings.try_into::<Config>().expect("parsing config")
+}
+
+#[test]
+fn single_worker_section() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[worker]
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+count = 2
+"#,
+    );
+    let groups = config.all_worker_groups();
+    assert_eq!(1, groups.len());
+    assert_eq!("svc", groups[0].name);
+    assert_eq!(2, groups[0].worker.count);
+}
+
+#[test]
+fn multiple_worker_groups() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[[worker_group]]
+name = "web"
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+count = 3
+ack_timeout = "2s"
+
+[[worker_group]]
+name = "jobs"
+type = "program"
+cmdline = ["/bin/false"]
+env = {}
+"#,
+    );
+    let groups = config.all_worker_groups();
+    let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+    assert_eq!(vec!["web", "jobs"], names);
+    assert_eq!(3, groups[0].worker.count);
+    assert_eq!(Some(Duration::from_secs(2)), groups[0].worker.ack_timeout);
+    assert_eq!(1, groups[1].worker.count);
+    match &groups[1].worker.kind {
+        WorkerKind::Program(p) => assert_eq!(vec!["/bin/false".to_string()], p.cmdline),
+        #[allow(unreachable_patterns)]
+        kind => panic!("unexpected worker kind {:?}", kind),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn shared_preloader_group() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[[worker_group]]
+name = "web"
+type = "ruby"
+gemfile = "Gemfile"
+load = "app.rb"
+start_expression = "serve"
+
+[[worker_group]]
+name = "jobs"
+type = "shared_preloader"
+group = "web"
+start_expression = "work"
+"#,
+    );
+    let groups = config.all_worker_groups();
+    assert_eq!(
+        WorkerKind::SharedPreloader(SharedPreloader {
+            group: "web".to_string(),
+            start_expression: "work".to_string(),
+        }),
+        groups[1].worker.kind
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn ruby_options() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[[worker_group]]
+name = "bundled"
+type = "ruby"
+gemfile = "Gemfile"
+load = "app.rb"
+start_expression = "serve"
+
+[[worker_group]]
+name = "plain"
+type = "ruby"
+load = ["config/boot.rb", "config/environment.rb"]
+start_expression = "serve"
+ruby_flags = ["-W0"]
+env = { RAILS_ENV = "production" }
+cwd = "app"
+"#,
+    );
+    let rubies: Vec<Ruby> = config
+        .all_worker_groups()
+        .into_iter()
+        .map(|group| match group.worker.kind {
+            WorkerKind::Ruby(rb) => rb,
+            kind => panic!("unexpected worker kind {:?}", kind),
+        })
+        .collect();
+    assert_eq!(Some("Gemfile".into()), rubies[0].gemfile);
+    assert_eq!(vec![std::path::PathBuf::from("app.rb")], rubies[0].load);
+    assert_eq!(None, rubies[1].gemfile);
+    assert_eq!(
+        vec![
+            std::path::PathBuf::from("config/boot.rb"),
+            std::path::PathBuf::from("config/environment.rb")
+        ],
+        rubies[1].load
+    );
+    assert_eq!(vec!["-W0".to_string()], rubies[1].ruby_flags);
+    assert_eq!(
+        Some(&"production".to_string()),
+        rubies[1].env.get("RAILS_ENV")
+    );
+    assert_eq!(Some("app".into()), rubies[1].cwd);
+}
+
+#[test]
+fn ack_strategies() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[[worker_group]]
+name = "web"
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+ack = { method = "tcp", port = "800${worker_index}" }
+
+[[worker_group]]
+name = "jobs"
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+ack = { method = "delay", delay = "2s" }
+"#,
+    );
+    let acks: Vec<AckStrategy> = config
+        .all_worker_groups()
+        .into_iter()
+        .map(|group| match group.worker.kind {
+            WorkerKind::Program(p) => p.ack,
+            #[allow(unreachable_patterns)]
+            kind => panic!("unexpected worker kind {:?}", kind),
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            AckStrategy::Tcp {
+                host: "127.0.0.1".to_string(),
+                port: "800${worker_index}".to_string(),
+                interval: Duration::from_millis(100),
+            },
+            AckStrategy::Delay {
+                delay: Duration::from_secs(2)
+            },
+        ],
+        acks
+    );
+}
+
+#[test]
+fn old_ack_workers_setting() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[[worker_group]]
+name = "acking"
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+ack_workers = true
+
+[[worker_group]]
+name = "immediate"
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+ack_workers = false
+"#,
+    );
+    let acks: Vec<AckStrategy> = config
+        .all_worker_groups()
+        .into_iter()
+        .map(|group| match group.worker.kind {
+            WorkerKind::Program(p) => p.ack,
+            #[allow(unreachable_patterns)]
+            kind => panic!("unexpected worker kind {:?}", kind),
+        })
+        .collect();
+    assert_eq!(vec![AckStrategy::StatusFd, AckStrategy::Immediate], acks);
+}
+
+#[test]
+fn worker_signals() {
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+
+[worker]
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+"#,
+    );
+    assert_eq!(
+        vec![
+            (Signal::SIGHUP, WorkerOp::ReopenLogs),
+            (Signal::SIGUSR1, WorkerOp::DumpStatus),
+        ],
+        config.supervisor.worker_signals().unwrap()
+    );
+
+    let config = parse(
+        r#"
+[supervisor]
+name = "svc"
+worker_signals = { USR2 = "flush_cache", SIGTTOU = "drain" }
+
+[worker]
+type = "program"
+cmdline = ["/bin/true"]
+env = {}
+"#,
+    );
+    let mut signals = config.supervisor.worker_signals().unwrap();
+    signals.sort_by_key(|(signal, _)| format!("{}", signal));
+    assert_eq!(
+        vec![
+            (Signal::SIGTTOU, WorkerOp::Drain),
+            (
+                Signal::SIGUSR2,
+                WorkerOp::Custom {
+                    command: "flush_cache".to_string()
+                }
+            ),
+        ],
+        signals
+    );
+}
@@ -0,0 +1,246 @@
+#![cfg(target_os = "linux")]
+
+use kleinhirn::configuration::PreloaderProgram;
+use kleinhirn::preloader::machine::PreloaderState;
+use kleinhirn::preloader::{
+    boot_deadline, Preloader, PreloaderMessage, CAPABILITIES, PROTOCOL_VERSION,
+};
+use kleinhirn::process_control::{Message, ProcessControl};
+use kleinhirn::reaper::setup_child_exit_handler;
+use nix::unistd::Pid;
+use rusty_fork::*;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn args(cmd: &Command) -> Vec<&OsStr> {
+    cmd.get_args().collect()
+}
+
+fn env<'a>(cmd: &'a Command, name: &str) -> Option<&'a OsStr> {
+    cmd.get_envs()
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value)
+}
+
+#[test]
+fn program_command() {
+    let program = PreloaderProgram {
+        cmdline: vec!["./zygote".to_string(), "--fast".to_string()],
+        env: vec![("RAILS_ENV".to_string(), "production".to_string())]
+            .into_iter()
+            .collect(),
+        cwd: None,
+    };
+    let cmd = Preloader::program_command(
+        "web",
+        Some("abc123"),
+        &program,
+        Some(Path::new("/srv/web")),
+        "7",
+    )
+    .unwrap();
+    assert_eq!("./zygote", cmd.get_program());
+    assert_eq!(vec!["--fast"], args(&cmd));
+    assert_eq!(Some(Path::new("/srv/web")), cmd.get_current_dir());
+    assert_eq!(Some(OsStr::new("production")), env(&cmd, "RAILS_ENV"));
+    assert_eq!(Some(OsStr::new("7")), env(&cmd, "KLEINHIRN_STATUS_FD"));
+    assert_eq!(Some(OsStr::new("web")), env(&cmd, "KLEINHIRN_NAME"));
+    assert_eq!(Some(OsStr::new("abc123")), env(&cmd, "KLEINHIRN_VERSION"));
+
+    let cmd = Preloader::program_command("web", None, &program, None, "7").unwrap();
+    assert_eq!(None, env(&cmd, "KLEINHIRN_VERSION"));
+    assert_eq!(None, cmd.get_current_dir());
+
+    let empty = PreloaderProgram::default();
+    assert!(Preloader::program_command("web", None, &empty, None, "7").is_err());
+}
+
+#[test]
+fn python_command() {
+    let cmd = Preloader::python_command(
+        "web",
+        Some("abc123"),
+        Path::new("/usr/bin/python3"),
+        "app.server",
+        "main",
+        Path::new("/srv/web"),
+        "7",
+    );
+    assert_eq!("/usr/bin/python3", cmd.get_program());
+    let argv = args(&cmd);
+    assert_eq!(Some(&OsStr::new("-c")), argv.first());
+    assert!(argv[1]
+        .to_str()
+        .unwrap()
+        .contains("The kleinhirn preloader for python applications."));
+    assert_eq!(
+        vec![
+            "--status-fd",
+            "7",
+            "--name",
+            "web",
+            "--module",
+            "app.server",
+            "--start-callable",
+            "main",
+            "--code-version",
+            "abc123"
+        ],
+        argv[2..]
+    );
+    assert_eq!(Some(Path::new("/srv/web")), cmd.get_current_dir());
+
+    let cmd = Preloader::python_command(
+        "web",
+        None,
+        Path::new("python3"),
+        "app",
+        "main",
+        Path::new("."),
+        "7",
+    );
+    assert!(!args(&cmd).contains(&OsStr::new("--code-version")));
+}
+
+fn message(json: &str) -> PreloaderMessage {
+    serde_json::from_str(json).unwrap()
+}
+
+fn hello(protocol_version: u32, capabilities: &[&str]) -> PreloaderMessage {
+    message(
+        &serde_json::json!({
+            "action": "hello",
+            "protocol_version": protocol_version,
+            "capabilities": capabilities,
+        })
+        .to_string(),
+    )
+}
+
+#[test]
+fn refuses_preloaders_without_hello() {
+    let state = PreloaderState::starting().on_preloader_message(message(
+        r#"{"action":"loading","file":"config/environment.rb"}"#,
+    ));
+    match state {
+        PreloaderState::Failed(failed) => assert!(
+            failed.reason().contains("did not start with a hello"),
+            "{}",
+            failed.reason()
+        ),
+        state => panic!("unexpected state {:?}", state),
+    }
+}
+
+#[test]
+fn refuses_other_protocol_versions() {
+    let state = PreloaderState::starting().on_preloader_message(hello(PROTOCOL_VERSION + 1, &[]));
+    match state {
+        PreloaderState::Failed(failed) => assert!(
+            failed.reason().contains("protocol version"),
+            "{}",
+            failed.reason()
+        ),
+        state => panic!("unexpected state {:?}", state),
+    }
+}
+
+#[test]
+fn negotiates_capabilities() {
+    let state = PreloaderState::starting()
+        .on_preloader_message(hello(
+            PROTOCOL_VERSION,
+            &["ack_token", "time_travel", "spawn_expression"],
+        ))
+        .on_preloader_message(message(r#"{"action":"loading","file":"app.rb"}"#))
+        .on_preloader_message(message(r#"{"action":"ready"}"#));
+    match state {
+        PreloaderState::Ready(ready) => {
+            // Only the ones that both sides know about:
+            let capabilities = ready.into_capabilities();
+            assert_eq!(vec!["ack_token", "spawn_expression"], capabilities);
+            assert!(capabilities
+                .iter()
+                .all(|capability| CAPABILITIES.contains(&capability.as_str())));
+        }
+        state => panic!("unexpected state {:?}", state),
+    }
+}
+
+#[test]
+fn boot_deadline_without_timeouts() {
+    let started = Instant::now();
+    let current = (PathBuf::from("app.rb"), started);
+    assert_eq!(None, boot_deadline(started, None, None, None));
+    assert_eq!(None, boot_deadline(started, None, None, Some(&current)));
+    // A load timeout only applies while a file is loading:
+    assert_eq!(
+        None,
+        boot_deadline(started, None, Some(Duration::from_secs(1)), None)
+    );
+}
+
+#[test]
+fn boot_deadline_picks_the_earliest_timeout() {
+    let started = Instant::now();
+    let boot = Some(Duration::from_secs(60));
+    let load = Some(Duration::from_secs(10));
+
+    let (deadline, error) = boot_deadline(started, boot, load, None).unwrap();
+    assert_eq!(started + Duration::from_secs(60), deadline);
+    assert!(error.contains("before loading any file"), "{}", error);
+
+    // Loading a file that started early enough hits the load timeout:
+    let current = (PathBuf::from("app.rb"), started + Duration::from_secs(5));
+    let (deadline, error) = boot_deadline(started, boot, load, Some(&current)).unwrap();
+    assert_eq!(started + Duration::from_secs(15), deadline);
+    assert!(error.contains("to load \"app.rb\""), "{}", error);
+
+    // Loading a file close to the end runs into the boot timeout:
+    let current = (PathBuf::from("app.rb"), started + Duration::from_secs(55));
+    let (deadline, error) = boot_deadline(started, boot, load, Some(&current)).unwrap();
+    assert_eq!(started + Duration::from_secs(60), deadline);
+    assert!(error.contains("stuck loading \"app.rb\""), "{}", error);
+}
+
+rusty_fork_test! {
+    #[test]
+    fn forgets_tokens_of_workers_that_die_before_acking() {
+        let dir = std::env::temp_dir().join(format!("kleinhirn-preloader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("creating the module directory");
+        std::fs::write(dir.join("dies_early.py"), "import os\n\ndef main():\n    os._exit(3)\n")
+            .expect("writing the module");
+
+        smol::run(async {
+            let mut zombies = setup_child_exit_handler().expect("setting up the reaper");
+            let mut preloader = Preloader::for_python(
+                "test",
+                None,
+                Path::new("python3"),
+                "dies_early",
+                "main",
+                &dir,
+            )
+            .expect("starting the preloader");
+            preloader.initialize(&|_| {}).await.expect("loading the module");
+            let spawned = preloader.spawn_process(0).await.expect("spawning");
+            let (id, pid) = match preloader.next_message().await.expect("launch message") {
+                Message::Launched { id, pid } => (id, pid),
+                msg => panic!("unexpected message {:?}", msg),
+            };
+            assert_eq!(spawned, id);
+            // The worker only gets re-parented to us once the
+            // preloader's intermediate process is gone:
+            let pid = Pid::from_raw(pid as i32);
+            while zombies.reap().await.expect("reaping") != pid {}
+            assert_eq!(1, preloader.pending_acks());
+
+            // What the supervisor does once it reaps the worker:
+            preloader.forget_worker(&id);
+            assert_eq!(0, preloader.pending_acks());
+        });
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,67 @@
+use kleinhirn::sd_notify::{self, Notifier};
+use std::os::unix::net::UnixDatagram;
+
+fn receive(socket: &UnixDatagram) -> String {
+    let mut buf = [0u8; 1024];
+    let len = socket.recv(&mut buf).expect("receiving a notification");
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[test]
+fn notifies_a_socket_path() {
+    let path = std::env::temp_dir().join(format!("kleinhirn-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).expect("binding the notification socket");
+
+    let notifier = Notifier::connect(path.to_str().unwrap()).expect("connecting");
+    notifier.ready().unwrap();
+    notifier
+        .status("web: WorkerSet::Running(acked:1)\nmore")
+        .unwrap();
+    notifier.watchdog().unwrap();
+    notifier.stopping().unwrap();
+
+    assert_eq!("READY=1", receive(&socket));
+    assert_eq!(
+        "STATUS=web: WorkerSet::Running(acked:1) more",
+        receive(&socket)
+    );
+    assert_eq!("WATCHDOG=1", receive(&socket));
+    assert_eq!("STOPPING=1", receive(&socket));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn notifies_an_abstract_socket() {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let name = format!("kleinhirn-notify-{}", std::process::id());
+    let addr = SocketAddr::from_abstract_name(&name).unwrap();
+    let socket = UnixDatagram::bind_addr(&addr).expect("binding the notification socket");
+
+    let notifier = Notifier::connect(&format!("@{}", name)).expect("connecting");
+    notifier.ready().unwrap();
+    assert_eq!("READY=1", receive(&socket));
+}
+
+#[test]
+fn parses_notifications() {
+    assert_eq!(
+        vec![("READY", "1"), ("STATUS", "serving = yes")],
+        sd_notify::parse("READY=1\nSTATUS=serving = yes\nnonsense\n")
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn listener_knows_the_sender() {
+    let listener = sd_notify::Listener::bind().expect("binding the listener");
+    let notifier = Notifier::connect(listener.address()).expect("connecting");
+    notifier.ready().unwrap();
+
+    let (pid, notification) = smol::run(listener.receive()).expect("receiving");
+    assert_eq!(std::process::id(), pid);
+    assert_eq!("READY=1", notification);
+}
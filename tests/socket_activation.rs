@@ -0,0 +1,40 @@
+use kleinhirn::socket_activation::ListenFds;
+
+#[test]
+fn parses_listen_fds() {
+    let listen_fds = ListenFds::from_vars(42, Some("42"), Some("2"), Some("http:https"))
+        .unwrap()
+        .expect("sockets for this process");
+    assert_eq!(2, listen_fds.count());
+    assert_eq!(Some("http:https"), listen_fds.names());
+
+    let listen_fds = ListenFds::from_vars(42, Some("42"), Some("1"), None)
+        .unwrap()
+        .expect("sockets for this process");
+    assert_eq!(1, listen_fds.count());
+    assert_eq!(None, listen_fds.names());
+}
+
+#[test]
+fn ignores_sockets_for_other_processes() {
+    assert_eq!(
+        None,
+        ListenFds::from_vars(42, None, Some("2"), None).unwrap()
+    );
+    assert_eq!(
+        None,
+        ListenFds::from_vars(42, Some("41"), Some("2"), Some("http:https")).unwrap()
+    );
+    assert_eq!(
+        None,
+        ListenFds::from_vars(42, Some("not a pid"), Some("2"), None).unwrap()
+    );
+}
+
+#[test]
+fn refuses_broken_listen_fds() {
+    assert!(ListenFds::from_vars(42, Some("42"), None, None).is_err());
+    assert!(ListenFds::from_vars(42, Some("42"), Some("two"), None).is_err());
+    assert!(ListenFds::from_vars(42, Some("42"), Some("2"), Some("http")).is_err());
+    assert!(ListenFds::from_vars(42, Some("42"), Some("1"), Some("http:https")).is_err());
+}
@@ -0,0 +1,94 @@
+use kleinhirn::template::{expand_version, TemplateError, Variables};
+use matches::assert_matches;
+use std::{env, fs};
+
+fn variables() -> Variables<'static> {
+    Variables {
+        worker_id: "abc",
+        worker_index: 3,
+        name: "web",
+        version: Some("v1"),
+    }
+}
+
+#[test]
+fn expands_variables() {
+    let vars = variables();
+    assert_eq!(
+        Ok("--port=8003".to_string()),
+        vars.expand("--port=800${worker_index}")
+    );
+    assert_eq!(
+        Ok("web/v1 abc".to_string()),
+        vars.expand("${name}/${version} ${worker_id}")
+    );
+    assert_eq!(Ok("costs $5".to_string()), vars.expand("costs $$5"));
+    assert_eq!(Ok("$HOME".to_string()), vars.expand("$HOME"));
+}
+
+#[test]
+fn escapes_shell_expansions() {
+    let vars = variables();
+    assert_eq!(
+        Ok("echo $$ ${HOME} $HOME".to_string()),
+        vars.expand("echo $$$$ $${HOME} $HOME")
+    );
+}
+
+#[test]
+fn expands_environment_variables() {
+    env::set_var("KLEINHIRN_TEMPLATE_TEST", "hi");
+    assert_eq!(
+        Ok("hi there".to_string()),
+        variables().expand("${env:KLEINHIRN_TEMPLATE_TEST} there")
+    );
+    assert_eq!(
+        Err(TemplateError::UnsetEnvironmentVariable(
+            "KLEINHIRN_TEMPLATE_UNSET".to_string()
+        )),
+        variables().expand("${env:KLEINHIRN_TEMPLATE_UNSET}")
+    );
+}
+
+#[test]
+fn rejects_bad_references() {
+    let vars = variables();
+    assert_eq!(
+        Err(TemplateError::UnknownVariable("nope".to_string())),
+        vars.expand("${nope}")
+    );
+    assert_eq!(
+        Err(TemplateError::Unterminated("${name".to_string())),
+        vars.expand("${name")
+    );
+    let unversioned = Variables {
+        version: None,
+        ..vars
+    };
+    assert_eq!(
+        Err(TemplateError::NoVersion),
+        unversioned.expand("${version}")
+    );
+}
+
+#[test]
+fn expands_versions() {
+    let dir = env::temp_dir().join(format!("kleinhirn-version-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("REVISION"), "abc123\n").unwrap();
+    env::set_var("KLEINHIRN_VERSION_TEST", "v2");
+
+    assert_eq!(
+        Ok("v2-abc123".to_string()),
+        expand_version("${env:KLEINHIRN_VERSION_TEST}-${file:REVISION}", &dir)
+    );
+    assert_matches!(
+        expand_version("${file:MISSING}", &dir),
+        Err(TemplateError::UnreadableFile(_, _))
+    );
+    assert_eq!(
+        Err(TemplateError::UnknownVariable("name".to_string())),
+        expand_version("${name}", &dir)
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
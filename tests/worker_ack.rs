@@ -0,0 +1,117 @@
+use kleinhirn::worker_ack::{AckDetails, WorkerControlMessage, WorkerOp, WorkerRequest};
+use std::time::Duration;
+
+#[test]
+fn parses_plain_acks() {
+    let msg: WorkerControlMessage = serde_json::from_str(r#"{"action":"ack","id":"abc"}"#).unwrap();
+    assert_eq!(
+        WorkerControlMessage::Ack {
+            id: "abc".to_string(),
+            token: None,
+            details: AckDetails::default(),
+        },
+        msg
+    );
+}
+
+#[test]
+fn parses_acks_with_versions() {
+    let msg: WorkerControlMessage = serde_json::from_str(
+        r#"{"action":"ack","id":"abc","version":"deadbeef","build_info":{"built_at":"today"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        WorkerControlMessage::Ack {
+            id: "abc".to_string(),
+            token: None,
+            details: AckDetails {
+                version: Some("deadbeef".to_string()),
+                build_info: Some(serde_json::json!({"built_at": "today"})),
+            },
+        },
+        msg
+    );
+}
+
+#[test]
+fn parses_acks_with_tokens() {
+    let msg: WorkerControlMessage =
+        serde_json::from_str(r#"{"action":"ack","id":"abc","token":"s3cr3t"}"#).unwrap();
+    assert_eq!(
+        WorkerControlMessage::Ack {
+            id: "abc".to_string(),
+            token: Some("s3cr3t".to_string()),
+            details: AckDetails::default(),
+        },
+        msg
+    );
+}
+
+#[test]
+fn parses_timeout_extensions() {
+    let msg: WorkerControlMessage =
+        serde_json::from_str(r#"{"action":"extend_timeout","id":"abc","by":"1m 30s"}"#).unwrap();
+    assert_eq!(
+        WorkerControlMessage::ExtendTimeout {
+            id: "abc".to_string(),
+            by: Duration::from_secs(90),
+        },
+        msg
+    );
+}
+
+#[test]
+fn parses_readiness_reports() {
+    let msg: WorkerControlMessage =
+        serde_json::from_str(r#"{"action":"unready","id":"abc","reason":"db down"}"#).unwrap();
+    assert_eq!(
+        WorkerControlMessage::Unready {
+            id: "abc".to_string(),
+            reason: "db down".to_string(),
+        },
+        msg
+    );
+    let msg: WorkerControlMessage =
+        serde_json::from_str(r#"{"action":"ready","id":"abc"}"#).unwrap();
+    assert_eq!(
+        WorkerControlMessage::Ready {
+            id: "abc".to_string()
+        },
+        msg
+    );
+}
+
+#[test]
+fn serializes_requests_to_workers() {
+    let request = WorkerRequest {
+        request: 3,
+        op: WorkerOp::named("drain"),
+    };
+    assert_eq!(
+        r#"{"request":3,"op":"drain"}"#,
+        serde_json::to_string(&request).unwrap()
+    );
+    let request = WorkerRequest {
+        request: 4,
+        op: WorkerOp::named("flush_cache"),
+    };
+    assert_eq!(
+        r#"{"request":4,"op":"custom","command":"flush_cache"}"#,
+        serde_json::to_string(&request).unwrap()
+    );
+}
+
+#[test]
+fn parses_replies() {
+    let msg: WorkerControlMessage =
+        serde_json::from_str(r#"{"action":"reply","id":"abc","request":3,"reply":{"jobs":2}}"#)
+            .unwrap();
+    assert_eq!(
+        WorkerControlMessage::Reply {
+            id: "abc".to_string(),
+            request: 3,
+            reply: serde_json::json!({"jobs": 2}),
+        },
+        msg
+    );
+}
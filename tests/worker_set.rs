@@ -1,24 +1,43 @@
 use kleinhirn::configuration;
 use kleinhirn::worker_set::{
-    Tick, Todo, WorkerAcked, WorkerDeath, WorkerLaunched, WorkerRequested, WorkerSet,
+    BootProgress, MiserableCondition, Recover, Tick, Todo, WorkerAcked, WorkerDeath,
+    WorkerExtendTimeout, WorkerKilled, WorkerLaunchFailure, WorkerLaunched, WorkerReadiness,
+    WorkerRequested, WorkerSet,
 };
 use matches::assert_matches;
 use nix::unistd::Pid;
 use std::time::{Duration, Instant};
 
+fn program_config(count: usize) -> configuration::WorkerConfig {
+    configuration::WorkerConfig {
+        count,
+        ack_timeout: None,
+        max_ack_timeout: None,
+        drain_timeout: None,
+        launch_timeout: None,
+        max_launch_failures: 3,
+        launch_retry_delay: Duration::from_secs(1),
+        on_ack_timeout: Default::default(),
+        failure_budget: 3,
+        max_lifetime: None,
+        lifetime_jitter: 0.0,
+        kind: configuration::WorkerKind::Program(configuration::Program {
+            cmdline: vec!["/bin/true".to_string()],
+            ..Default::default()
+        }),
+    }
+}
+
 #[must_use]
 fn ack_n_workers(mut machine: WorkerSet, from: usize, n: usize) -> WorkerSet {
     for i in dbg!(from)..=from + n - 1 {
         let id = format!("i:{}", i);
         let pid = dbg!(i);
-        assert_eq!(
-            Some(Todo::LaunchProcess),
-            machine.required_action().and_then(|todo| todo),
-            "i: {:?} machine {:?}",
-            i,
-            machine
-        );
-        machine = machine.on_worker_requested(WorkerRequested::new(id.to_string()));
+        let index = match machine.required_action().and_then(|todo| todo) {
+            Some(Todo::LaunchProcess(index)) => index,
+            todo => panic!("i: {:?} todo {:?} machine {:?}", i, todo, machine),
+        };
+        machine = machine.on_worker_requested(WorkerRequested::new(id.to_string(), index));
         machine = machine.on_worker_launched(WorkerLaunched::new(
             id.to_string(),
             Pid::from_raw(pid as i32),
@@ -30,18 +49,11 @@ fn ack_n_workers(mut machine: WorkerSet, from: usize, n: usize) -> WorkerSet {
 
 #[test]
 fn starts_workers_until_done() {
-    let config = configuration::WorkerConfig {
-        count: 3,
-        ack_timeout: None,
-        kind: configuration::WorkerKind::Program(configuration::Program {
-            cmdline: vec!["/bin/true".to_string()],
-            ..Default::default()
-        }),
-    };
+    let config = program_config(3);
     let mut machine = WorkerSet::new(config);
     assert_matches!(&machine, &WorkerSet::Startup(_));
     assert_eq!(
-        Some(Todo::LaunchProcess),
+        Some(Todo::LaunchProcess(0)),
         machine.required_action().and_then(|todo| todo)
     );
     machine = ack_n_workers(machine, 1, 2);
@@ -54,20 +66,19 @@ fn starts_workers_until_done() {
 
 #[test]
 fn keeps_them_running() {
-    let config = configuration::WorkerConfig {
-        count: 3,
-        ack_timeout: None,
-        kind: configuration::WorkerKind::Program(configuration::Program {
-            cmdline: vec!["/bin/true".to_string()],
-            ..Default::default()
-        }),
-    };
+    let config = program_config(3);
     let mut machine = WorkerSet::new(config);
     machine = ack_n_workers(machine, 1, 3);
     // kill the second worker:
     machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(2)));
     assert_matches!(&machine, &WorkerSet::Underprovisioned(_));
 
+    // its replacement gets the same slot:
+    assert_eq!(
+        Some(Todo::LaunchProcess(1)),
+        machine.required_action().and_then(|todo| todo)
+    );
+
     // start one up again:
     machine = ack_n_workers(machine, 4, 1);
 
@@ -77,14 +88,7 @@ fn keeps_them_running() {
 
 #[test]
 fn no_problems_with_unrelated_pids() {
-    let config = configuration::WorkerConfig {
-        count: 3,
-        ack_timeout: None,
-        kind: configuration::WorkerKind::Program(configuration::Program {
-            cmdline: vec!["/bin/true".to_string()],
-            ..Default::default()
-        }),
-    };
+    let config = program_config(3);
     let mut machine = WorkerSet::new(config);
     machine = ack_n_workers(machine, 1, 3);
     // kill the second worker:
@@ -92,21 +96,53 @@ fn no_problems_with_unrelated_pids() {
     assert_matches!(&machine, &WorkerSet::Running(_));
 }
 
+#[test]
+fn ignores_acks_from_other_processes() {
+    let config = program_config(1);
+    let mut machine = WorkerSet::new(config);
+    let id = "a".to_string();
+    machine = machine.on_worker_requested(WorkerRequested::new(id.clone(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new(id.clone(), Pid::from_raw(1)));
+
+    machine = machine.on_worker_acked(WorkerAcked::new(id.clone()).with_sender(Pid::from_raw(2)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    machine = machine.on_worker_acked(WorkerAcked::new(id).with_sender(Pid::from_raw(1)));
+    assert_matches!(&machine, &WorkerSet::Running(_));
+}
+
+#[test]
+fn holds_acks_that_arrive_before_the_launch() {
+    let config = program_config(2);
+    let mut machine = WorkerSet::new(config);
+    for (id, index) in &[("a", 0), ("b", 1)] {
+        machine = machine.on_worker_requested(WorkerRequested::new(id.to_string(), *index));
+    }
+
+    machine =
+        machine.on_worker_acked(WorkerAcked::new("a".to_string()).with_sender(Pid::from_raw(1)));
+    machine =
+        machine.on_worker_acked(WorkerAcked::new("b".to_string()).with_sender(Pid::from_raw(9)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    // The ack counts once the launch shows that it came from the worker:
+    machine = machine.on_worker_launched(WorkerLaunched::new("a".to_string(), Pid::from_raw(1)));
+    machine = machine.on_worker_launched(WorkerLaunched::new("b".to_string(), Pid::from_raw(2)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    machine =
+        machine.on_worker_acked(WorkerAcked::new("b".to_string()).with_sender(Pid::from_raw(2)));
+    assert_matches!(&machine, &WorkerSet::Running(_));
+}
+
 #[test]
 fn ack_timeouts() {
-    let config = configuration::WorkerConfig {
-        count: 1,
-        ack_timeout: Some(Duration::from_secs(1)),
-        kind: configuration::WorkerKind::Program(configuration::Program {
-            cmdline: vec!["/bin/true".to_string()],
-            ..Default::default()
-        }),
-    };
+    let mut config = program_config(1);
+    config.ack_timeout = Some(Duration::from_secs(1));
     let mut machine = WorkerSet::new(config);
     let id = "a".to_string();
     // record a worker as launched:
     let now = Instant::now();
-    machine = machine.on_worker_requested(WorkerRequested::new(id.clone()));
+    machine = machine.on_worker_requested(WorkerRequested::new(id.clone(), 0));
     machine = machine.on_worker_launched(WorkerLaunched::new(id.clone(), Pid::from_raw(1)));
     let post_launch = Instant::now();
     machine = machine.on_tick(Tick::new(now)); // This is fine
@@ -115,3 +151,383 @@ fn ack_timeouts() {
     machine = machine.on_tick(Tick::new(post_launch + Duration::from_millis(1001))); // Now it's too late
     assert_matches!(&machine, &WorkerSet::Faulted(_));
 }
+
+#[test]
+fn extends_ack_timeouts_up_to_the_ceiling() {
+    let mut config = program_config(1);
+    config.ack_timeout = Some(Duration::from_secs(1));
+    config.max_ack_timeout = Some(Duration::from_secs(3));
+    let mut machine = WorkerSet::new(config);
+    let id = "a".to_string();
+    machine = machine.on_worker_requested(WorkerRequested::new(id.clone(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new(id.clone(), Pid::from_raw(1)));
+
+    // Asking for more time than the ceiling allows only gets the ceiling:
+    machine = machine.on_worker_extend_timeout(WorkerExtendTimeout::new(
+        id.clone(),
+        Duration::from_secs(10),
+    ));
+    let post_extension = Instant::now();
+    machine = machine.on_tick(Tick::new(post_extension + Duration::from_millis(2000)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    machine = machine.on_tick(Tick::new(post_extension + Duration::from_millis(3001)));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn ignores_extensions_without_a_ceiling() {
+    let mut config = program_config(1);
+    config.ack_timeout = Some(Duration::from_secs(1));
+    let mut machine = WorkerSet::new(config);
+    let id = "a".to_string();
+    machine = machine.on_worker_requested(WorkerRequested::new(id.clone(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new(id.clone(), Pid::from_raw(1)));
+    machine =
+        machine.on_worker_extend_timeout(WorkerExtendTimeout::new(id, Duration::from_secs(10)));
+    let post_extension = Instant::now();
+
+    machine = machine.on_tick(Tick::new(post_extension + Duration::from_millis(1001)));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn launch_timeouts() {
+    let mut config = program_config(1);
+    config.launch_timeout = Some(Duration::from_secs(1));
+    config.max_launch_failures = 1;
+    let mut machine = WorkerSet::new(config);
+    let now = Instant::now();
+    machine = machine.on_worker_requested(WorkerRequested::new("a".to_string(), 0));
+    let post_request = Instant::now();
+    machine = machine.on_tick(Tick::new(now));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    machine = machine.on_tick(Tick::new(post_request + Duration::from_millis(1001)));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn kills_workers_that_launch_after_being_given_up_on() {
+    let mut config = program_config(1);
+    config.launch_timeout = Some(Duration::from_secs(1));
+    let mut machine = WorkerSet::new(config);
+    machine = machine.on_worker_requested(WorkerRequested::new("a".to_string(), 0));
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(1001)));
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(2002)));
+    assert_eq!(
+        Some(Todo::LaunchProcess(0)),
+        machine.required_action().and_then(|todo| todo)
+    );
+
+    machine = machine.on_worker_launched(WorkerLaunched::new("a".to_string(), Pid::from_raw(7)));
+    assert_eq!(
+        Some(Todo::KillProcess(Pid::from_raw(7))),
+        machine.required_action().and_then(|todo| todo)
+    );
+    machine = machine.on_worker_killed(WorkerKilled::new(Pid::from_raw(7)));
+    assert_eq!(
+        Some(Todo::LaunchProcess(0)),
+        machine.required_action().and_then(|todo| todo)
+    );
+}
+
+#[test]
+fn retries_failed_launches() {
+    let mut config = program_config(1);
+    config.max_launch_failures = 2;
+    let mut machine = WorkerSet::new(config);
+
+    machine = machine.on_worker_requested(WorkerRequested::new("a".to_string(), 0));
+    machine = machine.on_worker_launch_failure(WorkerLaunchFailure::new(
+        Some("a".to_string()),
+        "ENOMEM".to_string(),
+    ));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    // The retry only happens after the delay:
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+    machine = machine.on_tick(Tick::new(Instant::now()));
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(1001)));
+    assert_eq!(
+        Some(Todo::LaunchProcess(0)),
+        machine.required_action().and_then(|todo| todo)
+    );
+
+    // A successful launch resets the count of consecutive failures:
+    machine = machine.on_worker_requested(WorkerRequested::new("b".to_string(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new("b".to_string(), Pid::from_raw(1)));
+    machine =
+        machine.on_worker_launch_failure(WorkerLaunchFailure::new(None, "EAGAIN".to_string()));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    machine =
+        machine.on_worker_launch_failure(WorkerLaunchFailure::new(None, "EAGAIN".to_string()));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn kills_launched_workers_that_failed() {
+    let mut machine = WorkerSet::new(program_config(1));
+    machine = machine.on_worker_requested(WorkerRequested::new("a".to_string(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new("a".to_string(), Pid::from_raw(1)));
+    machine = machine.on_worker_launch_failure(WorkerLaunchFailure::new(
+        Some("a".to_string()),
+        "wrong version".to_string(),
+    ));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    assert_eq!(
+        Some(Todo::KillProcess(Pid::from_raw(1))),
+        machine.required_action().and_then(|todo| todo)
+    );
+    machine = machine.on_worker_killed(WorkerKilled::new(Pid::from_raw(1)));
+    machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(1)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    // They count against the failure budget, even though each launch
+    // succeeded:
+    for i in 2..=4 {
+        let id = format!("i:{}", i);
+        machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_secs(i)));
+        machine = machine.on_worker_requested(WorkerRequested::new(id.to_string(), 0));
+        machine = machine
+            .on_worker_launched(WorkerLaunched::new(id.to_string(), Pid::from_raw(i as i32)));
+        machine = machine.on_worker_launch_failure(WorkerLaunchFailure::new(
+            Some(id),
+            "wrong version".to_string(),
+        ));
+    }
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn replaces_timed_out_workers() {
+    let mut config = program_config(1);
+    config.ack_timeout = Some(Duration::from_secs(1));
+    config.on_ack_timeout = configuration::AckTimeoutPolicy::Replace;
+    config.failure_budget = 1;
+    let mut machine = WorkerSet::new(config);
+
+    machine = machine.on_worker_requested(WorkerRequested::new("a".to_string(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new("a".to_string(), Pid::from_raw(1)));
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(1001)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    assert_eq!(
+        Some(Todo::KillProcess(Pid::from_raw(1))),
+        machine.required_action().and_then(|todo| todo)
+    );
+    machine = machine.on_worker_killed(WorkerKilled::new(Pid::from_raw(1)));
+    machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(1)));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+
+    // The replacement times out too, which exhausts the budget:
+    assert_eq!(
+        Some(Todo::LaunchProcess(0)),
+        machine.required_action().and_then(|todo| todo)
+    );
+    machine = machine.on_worker_requested(WorkerRequested::new("b".to_string(), 0));
+    machine = machine.on_worker_launched(WorkerLaunched::new("b".to_string(), Pid::from_raw(2)));
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(1001)));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn failure_budget_starts_over_once_fully_acked() {
+    let mut config = program_config(1);
+    config.ack_timeout = Some(Duration::from_secs(1));
+    config.on_ack_timeout = configuration::AckTimeoutPolicy::Replace;
+    config.failure_budget = 1;
+    let mut machine = WorkerSet::new(config);
+
+    for (id, pid) in &[("a", 1), ("c", 3)] {
+        machine = machine.on_worker_requested(WorkerRequested::new(id.to_string(), 0));
+        machine =
+            machine.on_worker_launched(WorkerLaunched::new(id.to_string(), Pid::from_raw(*pid)));
+        machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_millis(1001)));
+        assert_matches!(
+            &machine,
+            &WorkerSet::Startup(_) | &WorkerSet::Underprovisioned(_)
+        );
+        machine = machine.on_worker_killed(WorkerKilled::new(Pid::from_raw(*pid)));
+        machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(*pid)));
+
+        // The replacement acks, which gives the budget back:
+        machine = ack_n_workers(machine, *pid as usize + 1, 1);
+        assert_matches!(&machine, &WorkerSet::Running(_));
+        machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(*pid + 1)));
+        assert_matches!(&machine, &WorkerSet::Underprovisioned(_));
+    }
+}
+
+#[test]
+fn replaces_expired_workers() {
+    let mut config = program_config(2);
+    config.max_lifetime = Some(Duration::from_secs(10));
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 2);
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_secs(11)));
+    assert_matches!(&machine, &WorkerSet::Running(_));
+
+    // Replacements get launched before the old workers are killed:
+    machine = ack_n_workers(machine, 3, 2);
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    for _ in 1..=2 {
+        let pid = match machine.required_action().and_then(|todo| todo) {
+            Some(Todo::KillProcess(pid)) => pid,
+            todo => panic!("Expected to kill an old worker, got {:?}", todo),
+        };
+        assert!(pid.as_raw() <= 2, "{:?} is not an expired worker", pid);
+        machine = machine.on_worker_killed(WorkerKilled::new(pid));
+        machine = machine.on_worker_death(WorkerDeath::new(pid));
+        assert_matches!(&machine, &WorkerSet::Running(_));
+    }
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+}
+
+#[test]
+fn expired_workers_keep_their_slot_until_they_are_gone() {
+    let mut config = program_config(2);
+    config.max_lifetime = Some(Duration::from_secs(10));
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 2);
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_secs(11)));
+
+    // While the old workers run, their replacements get other slots:
+    assert_eq!(
+        Some(Todo::LaunchProcess(2)),
+        machine.required_action().and_then(|todo| todo)
+    );
+    machine = ack_n_workers(machine, 3, 2);
+    for pid in 1..=2 {
+        let pid = Pid::from_raw(pid);
+        machine = machine.on_worker_killed(WorkerKilled::new(pid));
+        machine = machine.on_worker_death(WorkerDeath::new(pid));
+    }
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+
+    // Once they're gone, the next replacement gets a freed-up slot:
+    machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(3)));
+    assert_eq!(
+        Some(Todo::LaunchProcess(0)),
+        machine.required_action().and_then(|todo| todo)
+    );
+}
+
+#[test]
+fn replacements_move_into_freed_up_slots() {
+    let mut config = program_config(2);
+    config.max_lifetime = Some(Duration::from_secs(10));
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 2);
+    machine = machine.on_tick(Tick::new(Instant::now() + Duration::from_secs(11)));
+    machine = ack_n_workers(machine, 3, 2);
+    machine = machine.on_worker_readiness(
+        WorkerReadiness::unready("i:4".to_string(), "warming up".to_string())
+            .with_sender(Pid::from_raw(4)),
+    );
+    assert_eq!(vec![(3, "warming up")], machine.unready());
+
+    // Once the old workers are gone, their replacements take over
+    // their slots:
+    for pid in 1..=2 {
+        let pid = Pid::from_raw(pid);
+        machine = machine.on_worker_killed(WorkerKilled::new(pid));
+        machine = machine.on_worker_death(WorkerDeath::new(pid));
+    }
+    assert_eq!(vec![(1, "warming up")], machine.unready());
+
+    // ...and hand them on to their own replacements:
+    machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(4)));
+    assert_eq!(
+        Some(Todo::LaunchProcess(1)),
+        machine.required_action().and_then(|todo| todo)
+    );
+}
+
+#[test]
+fn recovers_from_faults() {
+    let mut config = program_config(2);
+    config.max_launch_failures = 1;
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 1);
+    machine = machine.on_worker_requested(WorkerRequested::new("b".to_string(), 1));
+    machine = machine.on_worker_launched(WorkerLaunched::new("b".to_string(), Pid::from_raw(2)));
+    machine = machine.on_worker_launch_failure(WorkerLaunchFailure::new(
+        Some("b".to_string()),
+        "wrong version".to_string(),
+    ));
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+
+    // The surviving worker is kept, and the failed one gets replaced
+    // and forgotten:
+    machine = machine.on_recover(Recover);
+    assert_matches!(&machine, &WorkerSet::Underprovisioned(_));
+    machine = ack_n_workers(machine, 3, 1);
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    machine = machine.on_worker_death(WorkerDeath::new(Pid::from_raw(2)));
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+}
+
+#[test]
+fn does_not_recover_without_a_preloader() {
+    let config = program_config(2);
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 2);
+    machine = machine.on_miserable_condition(MiserableCondition::PreloaderDied);
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+    machine = machine.on_miserable_condition(MiserableCondition::PreloaderDied);
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+
+    machine = machine.on_recover(Recover);
+    assert_matches!(&machine, &WorkerSet::Faulted(_));
+}
+
+#[test]
+fn reports_boot_progress() {
+    let config = program_config(1);
+    let mut machine = WorkerSet::new(config);
+    assert_eq!(None, machine.booting());
+
+    let since = Instant::now();
+    machine = machine.on_boot_progress(BootProgress::loading("app.rb".to_string(), since));
+    assert_matches!(&machine, &WorkerSet::Startup(_));
+    assert_eq!(Some(("app.rb", since)), machine.booting());
+    assert!(format!("{:?}", machine).contains("booting: loading \"app.rb\""));
+
+    machine = machine.on_boot_progress(BootProgress::done());
+    assert_eq!(None, machine.booting());
+    machine = ack_n_workers(machine, 1, 1);
+    assert_matches!(&machine, &WorkerSet::Running(_));
+}
+
+#[test]
+fn tracks_worker_readiness() {
+    let config = program_config(2);
+    let mut machine = WorkerSet::new(config);
+    machine = ack_n_workers(machine, 1, 2);
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    assert!(machine.unready().is_empty());
+
+    machine = machine.on_worker_readiness(
+        WorkerReadiness::unready("i:2".to_string(), "lost the database".to_string())
+            .with_sender(Pid::from_raw(2)),
+    );
+    // Unready workers stay around, they just don't count as ready:
+    assert_matches!(&machine, &WorkerSet::Running(_));
+    assert_eq!(vec![(1, "lost the database")], machine.unready());
+    assert_eq!(None, machine.required_action().and_then(|todo| todo));
+
+    // Only the worker itself gets to say it's ready again:
+    machine = machine.on_worker_readiness(
+        WorkerReadiness::ready("i:2".to_string()).with_sender(Pid::from_raw(1)),
+    );
+    assert_eq!(vec![(1, "lost the database")], machine.unready());
+
+    machine = machine.on_worker_readiness(
+        WorkerReadiness::ready("i:2".to_string()).with_sender(Pid::from_raw(2)),
+    );
+    assert!(machine.unready().is_empty());
+}